@@ -1,10 +0,0 @@
-import sys
-
-from PIL import Image
-import numpy as np
-
-from matplotlib import pyplot as plt
-
-with Image.open(sys.argv[1]) as im:
-    im.convert("RGB")
-    im.save(sys.argv[2])
\ No newline at end of file
@@ -1,16 +1,26 @@
 use bevy::{
     asset::{AssetLoader, LoadedAsset},
     reflect::TypeUuid,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::{CompressedImageFormats, Image, ImageType},
+    },
 };
 
-use crate::helpers;
+use crate::helpers::{self, map::Palette};
 
 #[derive(TypeUuid)]
 #[uuid = "7593c756-2b62-44cb-a763-1a345f16e779"]
 pub struct MapAsset(pub helpers::map::Map<u8>);
 
-#[derive(Default)]
-pub struct MapLoader;
+/**
+ * Loads `.map` files (8-bit BMPs of environment indices) and coloured `.map.png` files, whose
+ * environments are given by the palette. A PNG map is also its own texture, labeled `texture`.
+ */
+pub struct MapLoader {
+    pub palette: Palette,
+    pub texture: bool, // Textures can only be added if rendering
+}
 
 impl AssetLoader for MapLoader {
     fn load<'a>(
@@ -19,12 +29,109 @@ impl AssetLoader for MapLoader {
         load_context: &'a mut bevy::asset::LoadContext,
     ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
         Box::pin(async move {
-            let custom_asset = MapAsset(helpers::map::Map::from_bytes(bytes));
+            let is_png = load_context
+                .path()
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+            let custom_asset = if is_png {
+                let (map, image) = load_png_map(bytes, &self.palette)?;
+                if self.texture {
+                    load_context.set_labeled_asset("texture", LoadedAsset::new(image));
+                }
+                MapAsset(map)
+            } else {
+                MapAsset(helpers::map::Map::from_bytes(bytes, &self.palette)?)
+            };
             load_context.set_default_asset(LoadedAsset::new(custom_asset));
             Ok(())
         })
     }
     fn extensions(&self) -> &[&str] {
-        &["map"]
+        &["map", "map.png"]
+    }
+}
+
+/**
+ * Environment map and texture of a coloured PNG
+ */
+fn load_png_map(
+    bytes: &[u8],
+    palette: &Palette,
+) -> Result<(helpers::map::Map<u8>, Image), bevy::asset::Error> {
+    let image = Image::from_buffer(
+        bytes,
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        true,
+    )?;
+    let rgba = image
+        .convert(TextureFormat::Rgba8UnormSrgb)
+        .ok_or_else(|| bevy::asset::Error::msg("Unsupported PNG format"))?;
+    let size = rgba.texture_descriptor.size;
+    let map = helpers::map::Map::from_rgba(
+        size.height as usize,
+        size.width as usize,
+        &rgba.data,
+        palette,
+    );
+    Ok((map, image))
+}
+
+/**
+ * Texture of a generated map, one pixel per cell
+ */
+pub fn map_texture(map: &helpers::map::Map<u8>, palette: &Palette) -> Image {
+    let [rows, cols] = map.size();
+    Image::new(
+        Extent3d {
+            width: cols as u32,
+            height: rows as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        map.to_rgba(palette),
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/**
+ * Asset paths of the environment map and the texture of a `world.map`, which is either a PNG map
+ * or the common name of a `.map` and a `.bmp` file
+ */
+pub fn map_paths(map: &str) -> (String, String) {
+    if map.ends_with(".map.png") {
+        (map.to_string(), format!("{map}#texture"))
+    } else {
+        (format!("{map}.map"), format!("{map}.bmp"))
+    }
+}
+
+#[cfg(test)]
+mod assets_tests {
+    use super::*;
+
+    #[test]
+    fn test_png_map() {
+        let png = std::fs::read("assets/maps/01.map.png").unwrap();
+        let (map, _) = load_png_map(&png, &Palette::default()).unwrap();
+        let bmp = std::fs::read("assets/maps/01.map").unwrap();
+        let bmp = helpers::map::Map::from_bytes(&bmp, &Palette::default()).unwrap();
+        assert_eq!(map[0..1250], bmp[0..1250]);
+
+        // Meadow and water swap colours
+        let swapped = Palette {
+            meadow: [0, 90, 255],
+            water: [0, 156, 0],
+            ..Palette::default()
+        };
+        let (swapped, _) = load_png_map(&png, &swapped).unwrap();
+        for (a, b) in map[0..1250].iter().zip(&swapped[0..1250]) {
+            let expected = match a {
+                0 => 3,
+                3 => 0,
+                a => *a,
+            };
+            assert_eq!(*b, expected);
+        }
     }
 }
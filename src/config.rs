@@ -1,4 +1,6 @@
 use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
 pub const FPS: f32 = 30.0;
 pub const INITIAL_ENERGY: f32 = 80.0;
@@ -6,13 +8,90 @@ pub const MAX_ENERGY: f32 = 100.0;
 
 #[derive(Resource)]
 pub struct ConfigRes(pub crate::helpers::config_parser::Config);
+impl ConfigRes {
+    pub fn from_args(args: &crate::helpers::cli::Args) -> Self {
+        let mut config =
+            crate::helpers::config_parser::read_config(args.config.as_deref(), &args.overrides);
+        // Replays load no models and tournaments their own
+        if args.replay.is_some() || args.tournament.is_some() {
+            config.rl.load_path = None;
+        }
+        Self::validated(config)
+    }
+    /**
+     * Exits with a list of all invalid config values, so they are reported before the app starts
+     */
+    fn validated(config: crate::helpers::config_parser::Config) -> Self {
+        if let Err(errors) = config.validate() {
+            eprintln!("Invalid config:");
+            for error in errors {
+                eprintln!("    {error}");
+            }
+            std::process::exit(1);
+        }
+        Self(config)
+    }
+}
 impl Default for ConfigRes {
     fn default() -> Self {
-        Self(crate::helpers::config_parser::read_config())
+        Self::validated(crate::helpers::config_parser::read_config(None, &[]))
     }
 }
 
 #[derive(Resource, Default)]
 pub struct Map {
     pub map: Handle<crate::assets::MapAsset>,
+    pub texture: Handle<Image>, // Only set if rendering
+    pub drawn: bool, // Whether the texture shows the cells in palette colours, unlike a `.bmp`
+}
+
+/**
+ * Seed of the current generated map, present if `[world.generator]` is set. The first seed is the
+ * generator's own, or else drawn from the simulation's. Every later seed is drawn from the one
+ * before, so the maps of a run can be continued from any of its seeds.
+ */
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct MapGenerator {
+    pub seed: u64,
+}
+impl MapGenerator {
+    pub fn new(config: &crate::helpers::config_parser::GeneratorConfig, rng: &mut SimRng) -> Self {
+        Self {
+            seed: config
+                .seed
+                .unwrap_or_else(|| rng.0.gen_range(0..i64::MAX as u64)),
+        }
+    }
+    pub fn generate(
+        &self,
+        config: &crate::helpers::config_parser::GeneratorConfig,
+    ) -> crate::helpers::map::Map<u8> {
+        crate::helpers::generator::generate(config, &mut StdRng::seed_from_u64(self.seed))
+    }
+    /**
+     * Moves on to the seed of the next map. Seeds fit in TOML integers.
+     */
+    pub fn advance(&mut self) {
+        self.seed = StdRng::seed_from_u64(self.seed).gen_range(0..i64::MAX as u64);
+    }
+}
+
+/**
+ * Source of all randomness in the simulation and in training. Seeded from `world.seed` if set.
+ */
+#[derive(Resource)]
+pub struct SimRng(pub ChaCha12Rng);
+impl SimRng {
+    pub fn new(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => Self(ChaCha12Rng::seed_from_u64(seed)),
+            None => Self(ChaCha12Rng::from_entropy()),
+        }
+    }
+    /**
+     * Derives an independent stream, e.g. for a single agent inside a parallel iterator
+     */
+    pub fn derive(&mut self) -> ChaCha12Rng {
+        ChaCha12Rng::seed_from_u64(self.0.gen())
+    }
 }
@@ -1,33 +1,57 @@
+use std::collections::HashMap;
 use std::f32::consts::FRAC_PI_2;
 use std::num::Wrapping;
 
 use bevy::app::AppExit;
 use bevy::prelude::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::assets::MapAsset;
-use crate::config::{self, ConfigRes, Map, INITIAL_ENERGY, MAX_ENERGY};
+use crate::assets::{self, MapAsset};
+use crate::config::{self, ConfigRes, Map, MapGenerator, SimRng, INITIAL_ENERGY, MAX_ENERGY};
+use crate::evaluation::Evaluation;
+use crate::helpers::config_parser::WorldConfig;
 use crate::helpers::map;
 use crate::helpers::map::EnvType;
+use crate::metrics::{History, HistoryEntry, Metrics, MetricsLog, SpeciesSummary};
+use crate::replay::Replay;
+use crate::rl::checkpoint::{CheckpointManager, SimulationState, TrainingState};
 use crate::rl::model::NormalizationData;
 use crate::rl::{self, ModelPredator, ModelPrey, Transition};
+use crate::tournament::Tournament;
 
 use crate::states::{AppState, GameState};
 
 use self::go::control_agent;
+use self::grid::AgentGrid;
+use self::playback::{play_replay, record_frame};
 use self::preprocessing::{preprocess_predator, preprocess_prey};
+use self::ray_visuals::{render_rays, RayVisuals};
 use self::raycast::{cast_rays_hearing, cast_rays_vision, RayDetection};
-use self::spawning::batch_spawn;
+use self::spawning::{batch_spawn, insert_agent, AgentVisuals};
 
 mod bbox;
 mod go;
+mod grid;
 mod intersect;
+mod playback;
 mod preprocessing;
+mod ray_visuals;
 pub mod raycast;
 mod spawning;
 
+/**
+ * Frames between redraws of the ground texture as food is eaten and grows back
+ */
+const FOOD_TEXTURE_FRAMES: usize = 10;
+
 pub struct ResetEvent;
 
+/**
+ * Writes a checkpoint at the end of the frame
+ */
+pub struct SaveEvent;
+
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
 enum ExecSet {
     Prepare,   // Setup timers
@@ -36,19 +60,19 @@ enum ExecSet {
     Render,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Reflect)]
+#[derive(Debug, Clone, Copy, PartialEq, Reflect, Serialize, Deserialize)]
 pub enum AgentType {
     Prey,
     Predator,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Reflect, FromReflect)]
+#[derive(Debug, Clone, Copy, PartialEq, Reflect, FromReflect, Serialize, Deserialize)]
 pub enum TurnDirection {
     Left,
     Right,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Reflect)]
+#[derive(Debug, Clone, Copy, PartialEq, Reflect, Serialize, Deserialize)]
 pub enum Action {
     Turn(TurnDirection),
     Walk,
@@ -91,9 +115,19 @@ impl Action {
             Action::None => 10,
         }
     }
+    /**
+     * Same action, aimed at the new entity of its target, for agents restored from a checkpoint
+     */
+    pub fn remap(self, entities: &HashMap<Entity, Entity>) -> Self {
+        match self {
+            Action::Procreate(e) => Action::Procreate(e.and_then(|e| entities.get(&e).copied())),
+            Action::Eat(e) => Action::Eat(e.and_then(|e| entities.get(&e).copied())),
+            action => action,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Reflect, FromReflect)]
+#[derive(Clone, Debug, Reflect, FromReflect, Serialize, Deserialize)]
 pub struct AgentState {
     pub location: Vec2,
     pub direction: f32,
@@ -104,7 +138,7 @@ pub struct AgentState {
     pub hearing: Vec<RayDetection>,
 }
 
-#[derive(Component, Clone, Debug, Reflect)]
+#[derive(Component, Clone, Debug, Reflect, Serialize, Deserialize)]
 pub struct Agent {
     pub agent_type: AgentType,
     pub speed: f32,
@@ -114,8 +148,10 @@ pub struct Agent {
     pub energy: f32,
     pub alive: bool,
     pub life: usize,
+    pub age: usize,
     pub state: Option<AgentState>,
     pub previous_state: Option<AgentState>,
+    pub hidden: Vec<f32>, // Hidden state of a recurrent model, empty before the first action
 }
 impl Agent {
     pub fn new(t: AgentType, loc: Vec2, dir: f32, life: usize) -> Self {
@@ -128,8 +164,10 @@ impl Agent {
             energy: INITIAL_ENERGY,
             alive: true,
             life,
+            age: 0,
             state: None,
             previous_state: None,
+            hidden: Vec::new(),
         }
     }
     pub fn set_state(&mut self, new_state: AgentState) {
@@ -175,6 +213,10 @@ impl Default for FrameTimer {
     }
 }
 
+fn is_map_loaded(map_res: Res<Map>, map: Res<Assets<MapAsset>>) -> bool {
+    map.contains(&map_res.map)
+}
+
 fn should_run_frame(game_state: Res<State<GameState>>, timer: Res<FrameTimer>) -> bool {
     if game_state.0 == GameState::Normal {
         timer.timer.finished()
@@ -183,44 +225,97 @@ fn should_run_frame(game_state: Res<State<GameState>>, timer: Res<FrameTimer>) -
     }
 }
 
-pub struct EntityPlugin;
+/**
+ * Simulates the agents, or with `replay` only plays back a recorded episode. With `evaluate`
+ * the models are not updated and the simulation is reset after every evaluation episode.
+ */
+pub struct EntityPlugin {
+    pub headless: bool,
+    pub replay: bool,
+    pub evaluate: bool,
+}
 impl Plugin for EntityPlugin {
     fn build(&self, app: &mut App) {
+        if !self.headless {
+            app.init_resource::<AgentVisuals>()
+                .init_resource::<RayVisuals>()
+                .add_system(
+                    render_agents.in_set(ExecSet::Render).run_if(
+                        in_state(GameState::Normal).or_else(in_state(GameState::FastForward)),
+                    ),
+                )
+                .add_system(render_rays.in_set(ExecSet::Render));
+        }
         app.configure_sets(
             (
                 ExecSet::Prepare,
-                ExecSet::Calculate.run_if(should_run_frame),
+                ExecSet::Calculate
+                    .run_if(should_run_frame)
+                    .run_if(is_map_loaded),
                 ExecSet::Render,
-                ExecSet::Update.run_if(should_run_frame),
+                ExecSet::Update
+                    .run_if(should_run_frame)
+                    .run_if(is_map_loaded),
             )
                 .chain()
                 .in_set(OnUpdate(AppState::InGame)),
         )
         .register_type::<Agent>()
-        .add_event::<ResetEvent>()
-        .init_resource::<LearnLogT>()
         .init_resource::<LearnLog>()
+        .init_resource::<History>()
         .init_resource::<FrameTimer>()
-        .init_resource::<UpdateTimer>()
-        .init_resource::<ResetTimer>()
-        .add_startup_system(spawn_agents)
-        .add_system(update_frame_timer.in_set(ExecSet::Prepare))
-        .add_system(update_learn_log.in_set(ExecSet::Prepare))
-        .add_systems(
-            (preprocess_agents, move_agents)
-                .chain()
-                .in_set(ExecSet::Calculate),
-        )
-        .add_system(
-            render_agents
-                .in_set(ExecSet::Render)
-                .run_if(in_state(GameState::Normal).or_else(in_state(GameState::FastForward))),
-        )
-        .add_systems(
-            (update_models, reset_environment)
+        .add_event::<SaveEvent>()
+        .add_system(update_frame_timer.in_set(ExecSet::Prepare));
+        if self.replay {
+            app.add_system(play_replay.in_set(ExecSet::Calculate))
+                .add_system(
+                    replay_map
+                        .after(play_replay)
+                        .in_set(ExecSet::Calculate)
+                        .run_if(resource_exists::<MapGenerator>()),
+                );
+            return;
+        }
+        app.add_event::<ResetEvent>()
+            .init_resource::<LearnLogT>()
+            .init_resource::<Metrics>()
+            .init_resource::<AgentGrid>()
+            .init_resource::<UpdateTimer>()
+            .init_resource::<ResetTimer>()
+            .add_startup_system(spawn_agents.run_if(not(resource_exists::<SimulationState>())))
+            .add_startup_system(resume_training)
+            .add_system(update_learn_log.in_set(ExecSet::Prepare))
+            .add_system(
+                resume_food
+                    .in_set(ExecSet::Prepare)
+                    .run_if(resource_exists::<SimulationState>())
+                    .run_if(is_map_loaded),
+            )
+            .add_systems(
+                (preprocess_agents, move_agents)
+                    .chain()
+                    .in_set(ExecSet::Calculate),
+            )
+            .add_system(
+                regenerate_map
+                    .after(record_frame)
+                    .in_set(ExecSet::Update)
+                    .run_if(resource_exists::<MapGenerator>()),
+            );
+        if !self.headless {
+            app.add_system(render_food.in_set(ExecSet::Render));
+        }
+        if self.evaluate {
+            app.add_systems((record_frame, evaluate).chain().in_set(ExecSet::Update));
+            return;
+        }
+        app.add_systems(
+            (record_frame, update_models, reset_environment)
                 .chain()
                 .in_set(ExecSet::Update),
-        );
+        )
+        // Not in a set, so saving also works while paused
+        .add_system(save_checkpoint.in_base_set(CoreSet::PostUpdate));
     }
 }
 
@@ -239,71 +334,77 @@ fn preprocess_agents(
     mut query: Query<(Entity, &mut Agent)>,
     mut prey_buf: ResMut<rl::ReplayBufferPrey>,
     mut predator_buf: ResMut<rl::ReplayBufferPredator>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     mut res_ev: EventWriter<ResetEvent>,
-    assets: Res<AssetServer>,
+    mut metrics: ResMut<Metrics>,
+    mut grid: ResMut<AgentGrid>,
+    visuals: Option<Res<AgentVisuals>>,
     config: Res<ConfigRes>,
     map_res: Res<Map>,
-    map: Res<Assets<MapAsset>>,
+    mut map: ResMut<Assets<MapAsset>>,
 ) {
     for (e, mut a) in &mut query {
         if a.alive {
             a.life -= 1;
+            a.age += 1;
         }
         if a.life == 0 {
+            if a.alive {
+                let species = metrics.get_mut(a.agent_type);
+                species.old_age += 1;
+                species.end_episode(a.age);
+            }
             a.alive = false;
             commands.entity(e).despawn_recursive();
+            // Dying of old age is a time limit, not a terminal state
+            match a.agent_type {
+                AgentType::Prey => prey_buf.buffer.discard_pending(Some(e)),
+                AgentType::Predator => predator_buf.buffer.discard_pending(Some(e)),
+            }
         }
     }
-    if query
+    let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
+    let half_size = world_size / 2.0;
+    let world_borders = (-half_size, half_size);
+
+    let mut corpses = query
+        .iter()
+        .filter(|(_, a)| a.agent_type == AgentType::Prey && !a.alive)
+        .map(|(e, a)| (e, a.location, a.direction, a.energy))
+        .collect::<Vec<_>>();
+    let mut preys = query
+        .iter()
+        .filter(|(_, a)| a.agent_type == AgentType::Prey && a.alive)
+        .map(|(e, a)| (e, a.location, a.direction, a.energy, 0u32))
+        .collect::<Vec<_>>();
+    let predators = query
         .iter()
         .filter(|(_, a)| a.agent_type == AgentType::Predator && a.alive)
-        .count()
-        == 0
-        || query
-            .iter()
-            .filter(|(_, a)| a.agent_type == AgentType::Prey && a.alive)
-            .count()
-            == 0
-    {
+        .map(|(e, a)| (e, a.location, a.direction, a.energy))
+        .collect::<Vec<_>>();
+    // Also used for contact tests in `move_agents`, so it is rebuilt even when resetting
+    *grid = AgentGrid::new(&config.0, world_borders, &corpses, &preys, &predators);
+
+    if predators.is_empty() || preys.is_empty() {
         res_ev.send(ResetEvent);
     } else {
-        let map = map.get(&map_res.map).unwrap();
-        let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
-        let half_size = world_size / 2.0;
-
-        let world_borders = (-half_size, half_size);
-
-        let mut corpses = query
-            .iter()
-            .filter(|(_, a)| a.agent_type == AgentType::Prey && !a.alive)
-            .map(|(e, a)| (e, a.location, a.direction, a.energy))
-            .collect::<Vec<_>>();
-        let mut preys = query
-            .iter()
-            .filter(|(_, a)| a.agent_type == AgentType::Prey && a.alive)
-            .map(|(e, a)| (e, a.location, a.direction, a.energy, 0u32))
-            .collect::<Vec<_>>();
-        let predators = query
-            .iter()
-            .filter(|(_, a)| a.agent_type == AgentType::Predator && a.alive)
-            .map(|(e, a)| (e, a.location, a.direction, a.energy))
-            .collect::<Vec<_>>();
+        let map = map.get_mut(&map_res.map).unwrap();
+        if let Some(food) = &config.0.world.food {
+            map.0.regrow_food(food.regrowth / food.capacity);
+        }
 
         // Predators
         preprocess_predator(
             &mut commands,
             &mut query,
             &mut predator_buf,
-            &mut meshes,
-            &mut materials,
-            &assets,
+            &mut metrics.predator,
+            visuals.as_deref(),
             &config,
             map,
             &mut corpses,
             &mut preys,
             &predators,
+            &grid,
             world_borders,
             half_size,
         );
@@ -329,14 +430,14 @@ fn preprocess_agents(
             &mut commands,
             &mut query,
             &mut prey_buf,
-            &mut meshes,
-            &mut materials,
-            &assets,
+            &mut metrics.prey,
+            visuals.as_deref(),
             &config,
             map,
             &corpses,
             &preys,
             &predators,
+            &grid,
             world_borders,
             half_size,
         );
@@ -348,6 +449,8 @@ pub fn move_agents(
     config: Res<ConfigRes>,
     model_prey: Res<ModelPrey>,
     model_predator: Res<ModelPredator>,
+    mut rng: ResMut<SimRng>,
+    grid: Res<AgentGrid>,
     map_res: Res<Map>,
     map: Res<Assets<MapAsset>>,
 ) {
@@ -356,40 +459,65 @@ pub fn move_agents(
     let world_borders = (-half_size, half_size);
     let map = map.get(&map_res.map).unwrap();
 
-    let preys = query
-        .iter()
-        .filter(|(_, a)| a.agent_type == AgentType::Prey)
-        .map(|(e, a)| (e, a))
-        .collect::<Vec<_>>();
-    let predators = query
-        .iter()
-        .filter(|(_, a)| a.agent_type == AgentType::Predator)
-        .map(|(e, a)| (e, a))
-        .collect::<Vec<_>>();
+    let agents_by_entity = query.iter().collect::<HashMap<_, _>>();
     let agents = query.iter().collect::<Vec<_>>();
+    // Each agent gets its own stream, so the outcome does not depend on rayon's scheduling
+    let mut rngs = agents.iter().map(|_| rng.derive()).collect::<Vec<_>>();
+
+    // One batched forward pass per species
+    let mut actions = vec![None; agents.len()];
+    for (t, model, cfg) in [
+        (AgentType::Prey, &model_prey.model, &config.0.prey),
+        (
+            AgentType::Predator,
+            &model_predator.model,
+            &config.0.predator,
+        ),
+    ] {
+        let (indices, mut species_rngs): (Vec<_>, Vec<_>) = rngs
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| agents[*i].1.agent_type == t)
+            .unzip();
+        let states = indices
+            .iter()
+            .map(|i| agents[*i].1.state.as_ref().unwrap())
+            .collect::<Vec<_>>();
+        let hidden = indices
+            .iter()
+            .map(|i| agents[*i].1.hidden.as_slice())
+            .collect::<Vec<_>>();
+        let species_actions = model.get_actions(
+            &states,
+            &hidden,
+            cfg,
+            world_borders,
+            config.0.rl.learn,
+            &mut species_rngs,
+        );
+        for (i, action) in indices.into_iter().zip(species_actions) {
+            actions[i] = Some(action);
+        }
+    }
+
     let new_agents = agents
-        .par_iter()
-        .map(|(e, a)| {
-            let action = if a.agent_type == AgentType::Prey {
-                model_prey.model.get_action(
-                    a.state.as_ref().unwrap(),
-                    &config.0.prey,
-                    world_borders,
-                    config.0.rl.learn,
-                )
-            } else {
-                model_predator.model.get_action(
-                    a.state.as_ref().unwrap(),
-                    &config.0.predator,
-                    world_borders,
-                    config.0.rl.learn,
-                )
-            };
+        .into_par_iter()
+        .zip(actions)
+        .zip(rngs)
+        .map(|(((e, a), action), mut agent_rng)| {
+            let (action, hidden) = action.unwrap();
             // println!("Chosen action: {action:?}");
-            (
-                *e,
-                control_agent((*e, a), &config, &preys, &predators, action, map),
-            )
+            let mut new_agent = control_agent(
+                (e, a),
+                &config,
+                &grid,
+                &agents_by_entity,
+                action,
+                map,
+                &mut agent_rng,
+            );
+            new_agent.hidden = hidden;
+            (e, new_agent)
         })
         .collect::<Vec<_>>();
     for ((_, mut a), (_, new_agent)) in query.iter_mut().zip(new_agents) {
@@ -422,9 +550,16 @@ fn update_models(
     mut model_predator: ResMut<ModelPredator>,
     mut update_timer: ResMut<UpdateTimer>,
     mut exit: EventWriter<AppExit>,
+    mut save: EventWriter<SaveEvent>,
     mut logt: ResMut<LearnLogT>,
-    prey_buf: Res<rl::ReplayBufferPrey>,
-    predator_buf: Res<rl::ReplayBufferPredator>,
+    mut rng: ResMut<SimRng>,
+    mut prey_buf: ResMut<rl::ReplayBufferPrey>,
+    mut predator_buf: ResMut<rl::ReplayBufferPredator>,
+    mut metrics: ResMut<Metrics>,
+    mut metrics_log: ResMut<MetricsLog>,
+    mut history: ResMut<History>,
+    mut checkpoints: ResMut<CheckpointManager>,
+    agents: Query<&Agent>,
     config: Res<ConfigRes>,
 ) {
     update_timer.counter1 += 1;
@@ -433,102 +568,226 @@ fn update_models(
         update_timer.counter1.0 = 0;
         update_timer.counter2 += 1;
         logt.epoch = update_timer.counter2.0;
-        if update_timer.counter2.0 % cfg.updates_per_save == 0 {
-            model_prey.model.save(&cfg.save_path);
-            model_predator.model.save(&cfg.save_path);
-        }
         if update_timer.counter2.0 % cfg.updates_per_target == 0 {
             model_prey.model.reset_target();
             model_predator.model.reset_target();
         }
-        if !cfg.learn {
-            return;
+        let mut prey_stats = None;
+        let mut predator_stats = None;
+        if cfg.learn {
+            let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
+            let half_size = world_size / 2.0;
+            let world_borders = (-half_size, half_size);
+
+            let swap = if let Some(s) = cfg.updates_per_swap {
+                Some((update_timer.counter2.0 / s) % 2)
+            } else {
+                None
+            };
+
+            if swap.is_none() || swap.unwrap() == 0 {
+                let stats = model_predator.model.train(
+                    &mut predator_buf.buffer,
+                    &NormalizationData::new(&config.0.predator, world_borders),
+                    &config.0.rl,
+                    &mut rng.0,
+                );
+                logt.predator_loss = stats.loss;
+                predator_stats = Some(stats);
+            }
+            if swap.is_none() || swap.unwrap() == 1 {
+                let stats = model_prey.model.train(
+                    &mut prey_buf.buffer,
+                    &NormalizationData::new(&config.0.prey, world_borders),
+                    &config.0.rl,
+                    &mut rng.0,
+                );
+                logt.prey_loss = stats.loss;
+                prey_stats = Some(stats);
+            }
         }
-        let mut rng = rand::thread_rng();
-        let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
-        let half_size = world_size / 2.0;
-        let world_borders = (-half_size, half_size);
-
-        let predator_buf = predator_buf.buffer.get();
-        // println!("Predator: {:?}", predator_buf.iter().map(|a| a.action).collect::<Vec<_>>());
-        let prey_buf = prey_buf.buffer.get();
-        // println!("Prey: {:?}", prey_buf.iter().map(|a| a.action).collect::<Vec<_>>());
-        let swap = if let Some(s) = cfg.updates_per_swap {
-            Some((update_timer.counter2.0 / s) % 2)
-        } else {
-            None
+        prey_buf.buffer.finish_update();
+        predator_buf.buffer.finish_update();
+
+        let population = |t: AgentType| {
+            agents
+                .iter()
+                .filter(|a| a.agent_type == t && a.alive)
+                .count()
         };
+        let mean_energy = |t: AgentType| {
+            let (sum, count) = agents
+                .iter()
+                .filter(|a| a.agent_type == t && a.alive)
+                .fold((0.0, 0), |(sum, count), a| (sum + a.energy, count + 1));
+            if count > 0 {
+                sum / count as f32
+            } else {
+                0.0
+            }
+        };
+        history.push(HistoryEntry {
+            population: (
+                population(AgentType::Prey) as f32,
+                population(AgentType::Predator) as f32,
+            ),
+            loss: (logt.prey_loss, logt.predator_loss),
+            energy: (
+                mean_energy(AgentType::Prey),
+                mean_energy(AgentType::Predator),
+            ),
+            eps: (model_prey.model.eps(), model_predator.model.eps()),
+        });
+        metrics_log.write(
+            update_timer.counter2.0,
+            &metrics,
+            SpeciesSummary {
+                population: population(AgentType::Prey),
+                stats: prey_stats,
+                eps: model_prey.model.eps(),
+            },
+            SpeciesSummary {
+                population: population(AgentType::Predator),
+                stats: predator_stats,
+                eps: model_predator.model.eps(),
+            },
+        );
+        checkpoints.track(&metrics);
+        *metrics = Metrics::default();
 
-        if swap.is_none() || swap.unwrap() == 0 {
-            logt.predator_loss = model_predator.model.backpropagate(
-                &predator_buf,
-                &NormalizationData {
-                    min_speed: 0.0,
-                    max_speed: config.0.predator.run_speed,
-                    min_loc: world_borders.0,
-                    max_loc: world_borders.1,
-                    min_energy: 0.0,
-                    max_energy: 100.0,
-                    min_dist: 0.0,
-                    max_dist: config.0.predator.vision_range,
-                },
-                &config.0.rl,
-                &mut rng,
-            );
+        let finished = cfg.learn && update_timer.counter2.0 >= cfg.num_updates;
+        // Saved after the update, so a resumed run continues with the next one
+        if update_timer.counter2.0 % cfg.updates_per_save == 0 || finished {
+            save.send(SaveEvent);
         }
-        if swap.is_none() || swap.unwrap() == 1 {
-            logt.prey_loss = model_prey.model.backpropagate(
-                &prey_buf,
-                &NormalizationData {
-                    min_speed: 0.0,
-                    max_speed: config.0.prey.run_speed,
-                    min_loc: world_borders.0,
-                    max_loc: world_borders.1,
-                    min_energy: 0.0,
-                    max_energy: 100.0,
-                    min_dist: 0.0,
-                    max_dist: config.0.prey.vision_range,
-                },
-                &config.0.rl,
-                &mut rng,
-            );
-        }
-
-        if update_timer.counter2.0 >= cfg.num_updates {
-            if update_timer.counter2.0 % cfg.updates_per_save != 0 {
-                model_prey.model.save(&cfg.save_path);
-                model_predator.model.save(&cfg.save_path);
-            }
+        if finished {
             exit.send(AppExit);
         }
     }
 }
 
+fn save_checkpoint(
+    mut events: EventReader<SaveEvent>,
+    mut checkpoints: ResMut<CheckpointManager>,
+    rng: Res<SimRng>,
+    model_prey: Res<ModelPrey>,
+    model_predator: Res<ModelPredator>,
+    prey_buf: Res<rl::ReplayBufferPrey>,
+    predator_buf: Res<rl::ReplayBufferPredator>,
+    update_timer: Res<UpdateTimer>,
+    reset_timer: Res<ResetTimer>,
+    config: Res<ConfigRes>,
+    generator: Option<Res<MapGenerator>>,
+    agents: Query<(Entity, &Agent)>,
+    (map_res, maps): (Res<Map>, Res<Assets<MapAsset>>),
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    checkpoints.save(
+        &config.0,
+        (&model_prey.model, &model_predator.model),
+        (&prey_buf.buffer, &predator_buf.buffer),
+        TrainingState {
+            update: update_timer.counter2.0,
+            frame: update_timer.counter1.0,
+            reset: reset_timer.counter.0,
+            map_seed: generator.map(|g| g.seed),
+        },
+        &SimulationState {
+            rng: rng.0.clone(),
+            agents: agents.iter().map(|(e, a)| (e, a.clone())).collect(),
+            food: maps.get(&map_res.map).unwrap().0.food().to_vec(),
+        },
+    );
+}
+
+/**
+ * Continues the counters of a resumed checkpoint and restores its agents, which get new entities.
+ * Models, buffers and the generator are loaded in `main`.
+ */
+fn resume_training(
+    mut commands: Commands,
+    state: Option<Res<TrainingState>>,
+    simulation: Option<Res<SimulationState>>,
+    visuals: Option<Res<AgentVisuals>>,
+    mut update_timer: ResMut<UpdateTimer>,
+    mut reset_timer: ResMut<ResetTimer>,
+    mut prey_buf: ResMut<rl::ReplayBufferPrey>,
+    mut predator_buf: ResMut<rl::ReplayBufferPredator>,
+) {
+    if let Some(state) = state {
+        update_timer.counter1 = Wrapping(state.frame);
+        update_timer.counter2 = Wrapping(state.update);
+        reset_timer.counter = Wrapping(state.reset);
+    }
+    let Some(simulation) = simulation else {
+        return;
+    };
+    let entities = simulation
+        .agents
+        .iter()
+        .map(|(e, _)| (*e, commands.spawn_empty().id()))
+        .collect::<HashMap<_, _>>();
+    for (e, agent) in &simulation.agents {
+        let mut agent = agent.clone();
+        agent.action = agent.action.remap(&entities);
+        insert_agent(&mut commands, visuals.as_deref(), entities[e], agent);
+    }
+    prey_buf.buffer.remap_entities(&entities);
+    predator_buf.buffer.remap_entities(&entities);
+}
+
+/**
+ * Restores the food of a resumed checkpoint once its map is loaded
+ */
+fn resume_food(
+    mut commands: Commands,
+    simulation: Res<SimulationState>,
+    map_res: Res<Map>,
+    mut maps: ResMut<Assets<MapAsset>>,
+) {
+    maps.get_mut(&map_res.map)
+        .unwrap()
+        .0
+        .set_food(simulation.food.clone());
+    commands.remove_resource::<SimulationState>();
+}
+
 fn respawn(
     mut commands: Commands,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<StandardMaterial>>,
     query: Query<Entity, With<Agent>>,
     config: Res<ConfigRes>,
-    assets: Res<AssetServer>,
+    visuals: Option<Res<AgentVisuals>>,
+    rng: ResMut<SimRng>,
+    mut prey_buf: ResMut<rl::ReplayBufferPrey>,
+    mut predator_buf: ResMut<rl::ReplayBufferPredator>,
+    mut maps: ResMut<Assets<MapAsset>>,
 ) {
     println!("Resetting environment");
     for e in query.iter() {
         commands.entity(e).despawn_recursive();
     }
-    spawn_agents(commands, meshes, materials, config, assets);
+    for (_, map) in maps.iter_mut() {
+        map.0.refill_food();
+    }
+    prey_buf.buffer.discard_pending(None);
+    predator_buf.buffer.discard_pending(None);
+    spawn_agents(commands, config, visuals, rng);
 }
 
 fn reset_environment(
     commands: Commands,
     mut reset_timer: ResMut<ResetTimer>,
     mut res_ev: EventReader<ResetEvent>,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<StandardMaterial>>,
     query: Query<Entity, With<Agent>>,
     config: Res<ConfigRes>,
-    assets: Res<AssetServer>,
+    visuals: Option<Res<AgentVisuals>>,
+    rng: ResMut<SimRng>,
     update_timer: Res<UpdateTimer>,
+    prey_buf: ResMut<rl::ReplayBufferPrey>,
+    predator_buf: ResMut<rl::ReplayBufferPredator>,
+    maps: ResMut<Assets<MapAsset>>,
 ) {
     let cfg = &config.0.rl;
     let mut cnt = 0u32;
@@ -537,71 +796,209 @@ fn reset_environment(
     }
     if cnt >= 1 {
         reset_timer.counter.0 = 0;
-        respawn(commands, meshes, materials, query, config, assets);
+        respawn(
+            commands,
+            query,
+            config,
+            visuals,
+            rng,
+            prey_buf,
+            predator_buf,
+            maps,
+        );
     } else if update_timer.counter1.0 % cfg.frames_per_update == 0 {
         reset_timer.counter += 1;
         if reset_timer.counter.0 % cfg.updates_per_reset == 0 {
             reset_timer.counter.0 = 0;
-            respawn(commands, meshes, materials, query, config, assets);
+            respawn(
+                commands,
+                query,
+                config,
+                visuals,
+                rng,
+                prey_buf,
+                predator_buf,
+                maps,
+            );
+        }
+    }
+}
+
+/**
+ * Ends an evaluation episode when a species dies out or after `episode_length` frames, and
+ * exits after the last one
+ */
+fn evaluate(
+    commands: Commands,
+    mut evaluation: ResMut<Evaluation>,
+    mut metrics: ResMut<Metrics>,
+    mut res_ev: EventReader<ResetEvent>,
+    mut exit: EventWriter<AppExit>,
+    agents: Query<&Agent>,
+    query: Query<Entity, With<Agent>>,
+    config: Res<ConfigRes>,
+    visuals: Option<Res<AgentVisuals>>,
+    rng: ResMut<SimRng>,
+    mut prey_buf: ResMut<rl::ReplayBufferPrey>,
+    mut predator_buf: ResMut<rl::ReplayBufferPredator>,
+    tournament: Option<ResMut<Tournament>>,
+    (mut prey_model, mut predator_model): (ResMut<rl::ModelPrey>, ResMut<rl::ModelPredator>),
+    maps: ResMut<Assets<MapAsset>>,
+) {
+    // Without updates rollouts would only grow
+    prey_buf.buffer.finish_update();
+    predator_buf.buffer.finish_update();
+    evaluation.frame += 1;
+    let extinct = res_ev.iter().count() > 0;
+    if !extinct && evaluation.frame < evaluation.episode_length {
+        return;
+    }
+    evaluation.end_episode(&metrics, agents.iter());
+    *metrics = Metrics::default();
+    if evaluation.is_finished() {
+        let Some(mut tournament) = tournament else {
+            evaluation.report();
+            exit.send(AppExit);
+            return;
+        };
+        if !tournament.end_match(&mut evaluation) {
+            tournament.report();
+            exit.send(AppExit);
+            return;
         }
+        (prey_model.model, predator_model.model) = tournament.models(&config.0);
+    }
+    respawn(
+        commands,
+        query,
+        config,
+        visuals,
+        rng,
+        prey_buf,
+        predator_buf,
+        maps,
+    );
+}
+
+/**
+ * Generates a new map in place of the current one after an extinction, if `regenerate` is set
+ */
+fn regenerate_map(
+    mut res_ev: EventReader<ResetEvent>,
+    mut generator: ResMut<MapGenerator>,
+    config: Res<ConfigRes>,
+    map_res: Res<Map>,
+    mut maps: ResMut<Assets<MapAsset>>,
+    images: Option<ResMut<Assets<Image>>>,
+) {
+    let world = &config.0.world;
+    let Some(cfg) = world.generator.as_ref().filter(|g| g.regenerate) else {
+        return;
+    };
+    if res_ev.iter().count() == 0 {
+        return;
+    }
+    generator.advance();
+    replace_map(generator.generate(cfg), world, &map_res, &mut maps, images);
+}
+
+/**
+ * Shows the generated map of the replayed frame, which changes where the recorded run generated a
+ * new map
+ */
+fn replay_map(
+    replay: Res<Replay>,
+    mut generator: ResMut<MapGenerator>,
+    config: Res<ConfigRes>,
+    map_res: Res<Map>,
+    mut maps: ResMut<Assets<MapAsset>>,
+    images: Option<ResMut<Assets<Image>>>,
+) {
+    let world = &config.0.world;
+    let (Some(cfg), Some(frame)) = (&world.generator, replay.shown) else {
+        return;
+    };
+    let Some(seed) = replay.map_seed(frame).filter(|s| *s != generator.seed) else {
+        return;
+    };
+    generator.seed = seed;
+    replace_map(generator.generate(cfg), world, &map_res, &mut maps, images);
+}
+
+/**
+ * Puts a generated map and its texture in place of the current ones
+ */
+fn replace_map(
+    generated: map::Map<u8>,
+    world: &WorldConfig,
+    map_res: &Map,
+    maps: &mut Assets<MapAsset>,
+    images: Option<ResMut<Assets<Image>>>,
+) {
+    if let Some(texture) = images.and_then(|i| i.into_inner().get_mut(&map_res.texture)) {
+        let palette = world.palette.clone().unwrap_or_default();
+        *texture = assets::map_texture(&generated, &palette);
+    }
+    if let Some(map) = maps.get_mut(&map_res.map) {
+        map.0 = generated;
+    }
+}
+
+/**
+ * Redraws the ground texture from the map as food is eaten and grows back, so eaten food cells
+ * fade to meadow. Textures of `.bmp` files are not drawn from the cells and are kept.
+ */
+fn render_food(
+    mut frames: Local<usize>,
+    config: Res<ConfigRes>,
+    map_res: Res<Map>,
+    mut maps: ResMut<Assets<MapAsset>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let world = &config.0.world;
+    *frames += 1;
+    if world.food.is_none() || !map_res.drawn || *frames < FOOD_TEXTURE_FRAMES {
+        return;
+    }
+    *frames = 0;
+    let (Some(map), Some(texture)) = (maps.get_mut(&map_res.map), images.get_mut(&map_res.texture))
+    else {
+        return;
+    };
+    if map.0.take_food_changed() {
+        let palette = world.palette.clone().unwrap_or_default();
+        *texture = assets::map_texture(&map.0, &palette);
     }
 }
 
 fn spawn_agents(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     config: Res<ConfigRes>,
-    assets: Res<AssetServer>,
+    visuals: Option<Res<AgentVisuals>>,
+    mut rng: ResMut<SimRng>,
 ) {
     let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
-    let prey_mesh = assets.load("models/deer.glb#Scene0");
-    let predator_mesh = assets.load("models/wolf.glb#Scene0");
-    let prey_scale = config.0.prey.size;
-    let predator_scale = config.0.predator.size;
-    let prey_bb = shape::Box {
-        min_x: -prey_scale / 2.0,
-        max_x: prey_scale / 2.0,
-        min_y: 0.0,
-        max_y: prey_scale * config.0.prey.hl_ratio,
-        min_z: -prey_scale * config.0.prey.wl_ratio / 2.0,
-        max_z: prey_scale * config.0.prey.wl_ratio / 2.0,
-    };
-    let predator_bb = shape::Box {
-        min_x: -predator_scale / 2.0,
-        max_x: predator_scale / 2.0,
-        min_y: 0.0,
-        max_y: predator_scale * config.0.predator.hl_ratio,
-        min_z: -predator_scale * config.0.predator.wl_ratio / 2.0,
-        max_z: predator_scale * config.0.predator.wl_ratio / 2.0,
-    };
 
     batch_spawn(
         &mut commands,
-        &mut meshes,
-        &mut materials,
+        visuals.as_deref(),
         world_size,
-        &prey_mesh,
-        prey_scale,
-        prey_bb,
         AgentType::Prey,
         config.0.prey.count,
         config.0.world.batch_spawn_count,
         config.0.world.batch_spawn_radius,
         config.0.prey.life,
+        &mut rng.0,
     );
     batch_spawn(
         &mut commands,
-        &mut meshes,
-        &mut materials,
+        visuals.as_deref(),
         world_size,
-        &predator_mesh,
-        predator_scale,
-        predator_bb,
         AgentType::Predator,
         config.0.predator.count,
         config.0.world.batch_spawn_count,
         config.0.world.batch_spawn_radius,
         config.0.prey.life,
+        &mut rng.0,
     );
 }
@@ -1,22 +1,54 @@
-use std::f32::consts::PI;
+use std::{collections::HashMap, f32::consts::PI};
 
 use bevy::prelude::*;
 use rand::seq::IteratorRandom;
+use rand_chacha::ChaCha12Rng;
 
 use crate::{assets::MapAsset, config::ConfigRes, helpers::map};
 
-use super::{bbox::get_intersecting_agents, Action, Agent, AgentType, TurnDirection};
+use super::{
+    bbox::get_intersecting_agents,
+    grid::{AgentGrid, SpatialGrid},
+    Action, Agent, AgentType, TurnDirection,
+};
+
+/**
+ * Agents of the given grids which may touch the selected agent
+ */
+fn nearby_agents<'a>(
+    selected: &(Entity, &Agent),
+    radius: f32,
+    grids: &[&SpatialGrid],
+    agents: &HashMap<Entity, &'a Agent>,
+) -> Vec<(Entity, &'a Agent)> {
+    grids
+        .iter()
+        .flat_map(|grid| {
+            grid.near(selected.1.location, radius)
+                .into_iter()
+                .map(|i| grid.entity(i))
+        })
+        .filter_map(|e| agents.get(&e).map(|a| (e, *a)))
+        .collect()
+}
 
 fn eat_predator(
     agent: &mut Agent,
     selected: &(Entity, &Agent),
-    preys: &[(Entity, &Agent)],
+    grid: &AgentGrid,
+    agents: &HashMap<Entity, &Agent>,
     bbox_shape_self: Vec2,
     bbox_shape_prey_alive: Vec2,
     bbox_shape_prey_dead: Vec2,
+    rng: &mut ChaCha12Rng,
 ) {
-    let mut rng = rand::thread_rng();
-
+    // Preys killed this frame are still in the grid of alive preys
+    let preys = nearby_agents(
+        selected,
+        grid.predators.radius(),
+        &[&grid.preys, &grid.corpses],
+        agents,
+    );
     let alive_targets = preys
         .iter()
         .filter(|a| a.1.alive)
@@ -28,7 +60,7 @@ fn eat_predator(
         bbox_shape_self,
         bbox_shape_prey_alive,
     );
-    if let Some(target) = alive_targets.iter().choose(&mut rng) {
+    if let Some(target) = alive_targets.iter().choose(rng) {
         agent.action = Action::Eat(Some(target.0));
     } else {
         let dead_targets = preys
@@ -42,7 +74,7 @@ fn eat_predator(
             bbox_shape_self,
             bbox_shape_prey_dead,
         );
-        if let Some(target) = dead_targets.iter().choose(&mut rng) {
+        if let Some(target) = dead_targets.iter().choose(rng) {
             agent.action = Action::Eat(Some(target.0));
         } else {
             agent.action = Action::Eat(None);
@@ -56,13 +88,12 @@ fn eat_prey(agent: &mut Agent) {
 pub fn control_agent(
     selected: (Entity, &Agent),
     config: &ConfigRes,
-    preys: &[(Entity, &Agent)],
-    predators: &[(Entity, &Agent)],
+    grid: &AgentGrid,
+    agents: &HashMap<Entity, &Agent>,
     action: Action,
     map: &MapAsset,
+    rng: &mut ChaCha12Rng,
 ) -> Agent {
-    let mut rng = rand::thread_rng();
-
     let mut agent = selected.1.clone();
     if !agent.alive {
         return agent;
@@ -136,10 +167,12 @@ pub fn control_agent(
             AgentType::Predator => eat_predator(
                 &mut agent,
                 &selected,
-                preys,
+                grid,
+                agents,
                 bbox_shape_self,
                 bbox_shape_prey_alive,
                 bbox_shape_prey_dead,
+                rng,
             ),
         }
     } else if matches!(action, Action::Procreate(_)) {
@@ -152,8 +185,15 @@ pub fn control_agent(
             config.0.predator.size * config.0.predator.wl_ratio,
         );
         let potential_targets = match selected.1.agent_type {
-            AgentType::Prey => preys,
-            AgentType::Predator => predators,
+            AgentType::Prey => {
+                nearby_agents(&selected, grid.preys.radius(), &[&grid.preys], agents)
+            }
+            AgentType::Predator => nearby_agents(
+                &selected,
+                grid.predators.radius(),
+                &[&grid.predators],
+                agents,
+            ),
         };
         let bbox_shape_self = match selected.1.agent_type {
             AgentType::Prey => bbox_shape_prey,
@@ -165,13 +205,13 @@ pub fn control_agent(
         };
         if let Some(target) = get_intersecting_agents(
             &selected,
-            potential_targets,
+            &potential_targets,
             bbox_shape_self,
             bbox_shape_other,
         )
         .iter()
         .filter(|a| a.1.alive)
-        .choose(&mut rng)
+        .choose(rng)
         {
             agent.action = Action::Procreate(Some(target.0));
         } else {
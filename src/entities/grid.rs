@@ -0,0 +1,255 @@
+use bevy::prelude::*;
+
+use crate::helpers::config_parser::Config;
+
+/**
+ * Uniform grid over the world. Every item is stored in all cells covered by the square around its
+ * location with the grid's radius, so any shape within that radius is found by a query of the
+ * cells it touches.
+ */
+#[derive(Default, Debug)]
+pub struct SpatialGrid {
+    min: Vec2,
+    cell_size: f32,
+    cols: usize,
+    rows: usize,
+    radius: f32,
+    cells: Vec<Vec<usize>>,
+    entities: Vec<Entity>,
+}
+impl SpatialGrid {
+    /**
+     * Items are indexed by their position in `items`
+     */
+    pub fn new(
+        world_borders: (Vec2, Vec2),
+        cell_size: f32,
+        radius: f32,
+        items: impl Iterator<Item = (Entity, Vec2)>,
+    ) -> Self {
+        // Items on the border stick out of the world by at most their radius
+        let min = world_borders.0 - radius;
+        let size = world_borders.1 + radius - min;
+        let cols = (size.x / cell_size).ceil().max(1.0) as usize;
+        let rows = (size.y / cell_size).ceil().max(1.0) as usize;
+        let mut grid = Self {
+            min,
+            cell_size,
+            cols,
+            rows,
+            radius,
+            cells: vec![Vec::new(); cols * rows],
+            entities: Vec::new(),
+        };
+        for (i, (e, location)) in items.enumerate() {
+            grid.entities.push(e);
+            let (from, to) = (grid.cell(location - radius), grid.cell(location + radius));
+            for y in from.1..=to.1 {
+                for x in from.0..=to.0 {
+                    let idx = grid.index(x, y).unwrap();
+                    grid.cells[idx].push(i);
+                }
+            }
+        }
+        grid
+    }
+    pub fn entity(&self, i: usize) -> Entity {
+        self.entities[i]
+    }
+    /**
+     * Cell containing a point, clamped to the grid
+     */
+    fn cell(&self, p: Vec2) -> (i64, i64) {
+        let c = ((p - self.min) / self.cell_size).floor();
+        (
+            (c.x as i64).clamp(0, self.cols as i64 - 1),
+            (c.y as i64).clamp(0, self.rows as i64 - 1),
+        )
+    }
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.cols as i64 || y >= self.rows as i64 {
+            None
+        } else {
+            Some(y as usize * self.cols + x as usize)
+        }
+    }
+    fn collect(mut found: Vec<usize>) -> Vec<usize> {
+        // Sorted, so candidates are checked in the same order as the full list
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+    /**
+     * Items which may be within `radius` of a location
+     */
+    pub fn near(&self, location: Vec2, radius: f32) -> Vec<usize> {
+        let (from, to) = (self.cell(location - radius), self.cell(location + radius));
+        let mut found = Vec::new();
+        for y in from.1..=to.1 {
+            for x in from.0..=to.0 {
+                found.extend(&self.cells[self.index(x, y).unwrap()]);
+            }
+        }
+        Self::collect(found)
+    }
+    /**
+     * Items which may intersect the segment from `start` to `end`, found by walking the cells
+     * the segment crosses (DDA)
+     */
+    pub fn along(&self, start: Vec2, end: Vec2) -> Vec<usize> {
+        let delta = end - start;
+        let unclamped = |p: Vec2| ((p - self.min) / self.cell_size).floor();
+        let (mut x, mut y) = {
+            let c = unclamped(start);
+            (c.x as i64, c.y as i64)
+        };
+        let end_cell = unclamped(end);
+        let end_cell = (end_cell.x as i64, end_cell.y as i64);
+        let step = (delta.x.signum() as i64, delta.y.signum() as i64);
+        // Fraction of the segment at which the next cell border is crossed, per axis
+        let first_border = |c: i64, step: i64, min: f32, start: f32, delta: f32| {
+            if delta == 0.0 {
+                f32::INFINITY
+            } else {
+                let border = min + (c + i64::from(step > 0)) as f32 * self.cell_size;
+                (border - start) / delta
+            }
+        };
+        let mut t_max = Vec2::new(
+            first_border(x, step.0, self.min.x, start.x, delta.x),
+            first_border(y, step.1, self.min.y, start.y, delta.y),
+        );
+        let t_delta = Vec2::new(
+            self.cell_size / delta.x.abs(),
+            self.cell_size / delta.y.abs(),
+        );
+
+        let mut found = Vec::new();
+        loop {
+            if let Some(idx) = self.index(x, y) {
+                found.extend(&self.cells[idx]);
+            }
+            if (x, y) == end_cell {
+                break;
+            }
+            if t_max.x < t_max.y {
+                if t_max.x > 1.0 {
+                    break;
+                }
+                x += step.0;
+                t_max.x += t_delta.x;
+            } else {
+                if t_max.y > 1.0 {
+                    break;
+                }
+                y += step.1;
+                t_max.y += t_delta.y;
+            }
+        }
+        Self::collect(found)
+    }
+    /**
+     * Radius of the items, which is also how far an agent of this grid reaches in contact tests
+     */
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+}
+
+/**
+ * Agents of the current frame by category, indexed like the lists used for raycasting. Rebuilt
+ * every frame in `preprocess_agents`.
+ */
+#[derive(Resource, Default, Debug)]
+pub struct AgentGrid {
+    pub corpses: SpatialGrid,
+    pub preys: SpatialGrid,
+    pub predators: SpatialGrid,
+}
+impl AgentGrid {
+    pub fn new(
+        config: &Config,
+        world_borders: (Vec2, Vec2),
+        corpses: &[(Entity, Vec2, f32, f32)],
+        preys: &[(Entity, Vec2, f32, f32, u32)],
+        predators: &[(Entity, Vec2, f32, f32)],
+    ) -> Self {
+        let cell_size = config.world.grid_cell_size;
+        // Half diagonal of the bounding box, preys use the larger of their alive and dead boxes
+        let prey_radius = Vec2::new(
+            config.prey.size,
+            config.prey.size * config.prey.wl_ratio.max(config.prey.hl_ratio),
+        )
+        .length()
+            / 2.0;
+        let predator_radius = Vec2::new(
+            config.predator.size,
+            config.predator.size * config.predator.wl_ratio,
+        )
+        .length()
+            / 2.0;
+        Self {
+            corpses: SpatialGrid::new(
+                world_borders,
+                cell_size,
+                prey_radius,
+                corpses.iter().map(|(e, l, ..)| (*e, *l)),
+            ),
+            preys: SpatialGrid::new(
+                world_borders,
+                cell_size,
+                prey_radius,
+                preys.iter().map(|(e, l, ..)| (*e, *l)),
+            ),
+            predators: SpatialGrid::new(
+                world_borders,
+                cell_size,
+                predator_radius,
+                predators.iter().map(|(e, l, ..)| (*e, *l)),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod grid_tests {
+    use super::*;
+
+    #[test]
+    fn test_queries() {
+        let borders = (Vec2::new(-10.0, -10.0), Vec2::new(10.0, 10.0));
+        let locations = [
+            Vec2::new(-8.0, -8.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(5.0, 5.0),
+            Vec2::new(8.0, -8.0),
+        ];
+        let grid = SpatialGrid::new(
+            borders,
+            2.0,
+            0.5,
+            locations
+                .iter()
+                .enumerate()
+                .map(|(i, l)| (Entity::from_raw(i as u32), *l)),
+        );
+        assert_eq!(grid.near(Vec2::new(0.4, -0.4), 0.5), vec![1]);
+        assert_eq!(grid.near(Vec2::new(9.0, -9.0), 1.0), vec![3]);
+        assert!(grid.near(Vec2::new(-4.0, 4.0), 1.0).is_empty());
+        // Diagonal through the first three, but not the last
+        assert_eq!(
+            grid.along(Vec2::new(-9.0, -9.0), Vec2::new(9.0, 9.0)),
+            vec![0, 1, 2]
+        );
+        // Backwards and ending before the third
+        assert_eq!(
+            grid.along(Vec2::new(3.0, 3.0), Vec2::new(-9.0, -9.0)),
+            vec![0, 1]
+        );
+        assert_eq!(
+            grid.along(Vec2::new(8.0, 9.0), Vec2::new(8.0, -20.0)),
+            vec![3]
+        );
+        assert_eq!(grid.entity(2), Entity::from_raw(2));
+    }
+}
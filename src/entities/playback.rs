@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::MapGenerator,
+    replay::{Recorder, Replay},
+};
+
+use super::{
+    spawning::{spawn, AgentVisuals},
+    Agent,
+};
+
+/**
+ * Id of a replayed agent in the replay file
+ */
+#[derive(Component)]
+pub struct ReplayAgent {
+    id: u64,
+}
+
+pub fn record_frame(
+    mut recorder: ResMut<Recorder>,
+    query: Query<(Entity, &Agent)>,
+    generator: Option<Res<MapGenerator>>,
+) {
+    recorder.write(query.iter(), generator.map(|g| g.seed));
+}
+
+/**
+ * Advances the replay and makes the agents match the shown frame, spawning and despawning them
+ * as needed
+ */
+pub fn play_replay(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    mut query: Query<(Entity, &mut Agent, &ReplayAgent)>,
+    visuals: Option<Res<AgentVisuals>>,
+) {
+    // The first frame is shown before advancing
+    if replay.shown.is_some() {
+        replay.advance();
+    }
+    let frame = replay.frame();
+    if replay.shown == Some(frame) {
+        return;
+    }
+    replay.shown = Some(frame);
+
+    let mut records = replay.frames[frame]
+        .agents
+        .iter()
+        .map(|r| (r.id, r))
+        .collect::<HashMap<_, _>>();
+    for (e, mut agent, replay_agent) in &mut query {
+        match records.remove(&replay_agent.id) {
+            Some(record) => *agent = record.to_agent(),
+            None => commands.entity(e).despawn_recursive(),
+        }
+    }
+    let mut new_agents = records.into_values().collect::<Vec<_>>();
+    // Sorted, so spawning does not depend on the iteration order of the map
+    new_agents.sort_by_key(|r| r.id);
+    for record in new_agents {
+        let agent = record.to_agent();
+        let e = spawn(
+            &mut commands,
+            visuals.as_deref(),
+            agent.agent_type,
+            agent.location,
+            agent.direction,
+            agent.life,
+        );
+        commands
+            .entity(e)
+            .insert((agent, ReplayAgent { id: record.id }));
+    }
+}
@@ -1,32 +1,26 @@
-use crate::{assets::MapAsset, helpers::config_parser::AgentConfig};
+use crate::{assets::MapAsset, helpers::config_parser::AgentConfig, metrics::SpeciesMetrics};
 
-use super::{raycast::Detection, spawning::spawn, *};
+use super::{
+    raycast::Detection,
+    spawning::{spawn, AgentVisuals},
+    *,
+};
 
 pub fn preprocess_predator(
     commands: &mut Commands,
     query: &mut Query<(Entity, &mut Agent)>,
     buf: &mut ResMut<rl::ReplayBufferPredator>,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    assets: &Res<AssetServer>,
+    metrics: &mut SpeciesMetrics,
+    visuals: Option<&AgentVisuals>,
     config: &Res<ConfigRes>,
     map: &MapAsset,
     corpses: &mut [(Entity, Vec2, f32, f32)],
     preys: &mut [(Entity, Vec2, f32, f32, u32)],
     predators: &[(Entity, Vec2, f32, f32)],
+    grid: &AgentGrid,
     world_borders: (Vec2, Vec2),
     half_size: Vec2,
 ) {
-    let predator_mesh = assets.load("models/wolf.glb#Scene0");
-    let predator_scale = config.0.predator.size;
-    let predator_bb = shape::Box {
-        min_x: -predator_scale / 2.0,
-        max_x: predator_scale / 2.0,
-        min_y: 0.0,
-        max_y: predator_scale * config.0.predator.hl_ratio,
-        min_z: -predator_scale * config.0.predator.wl_ratio / 2.0,
-        max_z: predator_scale * config.0.predator.wl_ratio / 2.0,
-    };
     let mut predator_procreations =
         std::collections::HashMap::<Entity, bool>::with_capacity(predators.len());
     query
@@ -93,16 +87,13 @@ pub fn preprocess_predator(
                         if e < e_partner {
                             spawn(
                                 commands,
-                                meshes,
-                                materials,
-                                &predator_mesh,
-                                predator_scale,
-                                predator_bb,
+                                visuals,
                                 AgentType::Predator,
                                 a.location,
                                 a.direction,
                                 cfg.life,
                             );
+                            metrics.births += 1;
                         }
                         has_procreated = true;
                     }
@@ -153,6 +144,7 @@ pub fn preprocess_predator(
                 corpses,
                 preys,
                 predators,
+                grid,
                 &config.0,
             ),
             hearing: cast_rays_hearing(
@@ -167,6 +159,7 @@ pub fn preprocess_predator(
                 corpses,
                 preys,
                 predators,
+                grid,
                 &config.0,
             ),
         };
@@ -182,17 +175,28 @@ pub fn preprocess_predator(
             false,
             cfg,
         );
+        metrics.add_reward(reward);
+        if has_eaten {
+            metrics.eaten += 1;
+        }
         if a.energy <= 0.0 {
             commands.entity(e).despawn_recursive();
+            metrics.starvations += 1;
+            metrics.end_episode(a.age);
         }
 
         if let Some(previous_state) = &a.previous_state {
-            buf.buffer.add(Transition {
-                state: previous_state.clone(),
-                action: a.action,
-                reward,
-                next_state: a.state.as_ref().unwrap().clone(),
-            });
+            buf.buffer.push(
+                e,
+                Transition {
+                    state: previous_state.clone(),
+                    action: a.action,
+                    reward,
+                    next_state: a.state.as_ref().unwrap().clone(),
+                    done: a.energy <= 0.0,
+                    steps: 1,
+                },
+            );
         }
     }
 }
@@ -201,27 +205,17 @@ pub fn preprocess_prey(
     commands: &mut Commands,
     query: &mut Query<(Entity, &mut Agent)>,
     buf: &mut ResMut<rl::ReplayBufferPrey>,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    assets: &Res<AssetServer>,
+    metrics: &mut SpeciesMetrics,
+    visuals: Option<&AgentVisuals>,
     config: &Res<ConfigRes>,
-    map: &MapAsset,
+    map: &mut MapAsset,
     corpses: &[(Entity, Vec2, f32, f32)],
     preys: &[(Entity, Vec2, f32, f32, u32)],
     predators: &[(Entity, Vec2, f32, f32)],
+    grid: &AgentGrid,
     world_borders: (Vec2, Vec2),
     half_size: Vec2,
 ) {
-    let prey_mesh = assets.load("models/deer.glb#Scene0");
-    let prey_scale = config.0.prey.size;
-    let prey_bb = shape::Box {
-        min_x: -prey_scale / 2.0,
-        max_x: prey_scale / 2.0,
-        min_y: 0.0,
-        max_y: prey_scale * config.0.prey.hl_ratio,
-        min_z: -prey_scale * config.0.prey.wl_ratio / 2.0,
-        max_z: prey_scale * config.0.prey.wl_ratio / 2.0,
-    };
     let mut prey_procreations =
         std::collections::HashMap::<Entity, bool>::with_capacity(preys.len());
     query
@@ -269,11 +263,27 @@ pub fn preprocess_prey(
                     (world_borders.0.y, world_borders.1.y),
                 );
                 if current_env == EnvType::Food && a.energy < MAX_ENERGY {
-                    a.energy += cfg.eating_speed;
-                    if a.energy > MAX_ENERGY {
-                        a.energy = MAX_ENERGY;
+                    // Food is only taken from the cell as far as the prey can still eat
+                    let appetite = cfg.eating_speed.min(MAX_ENERGY - a.energy);
+                    let eaten = match &config.0.world.food {
+                        Some(food) => {
+                            food.capacity
+                                * map.0.eat_food(
+                                    a.location,
+                                    (world_borders.0.x, world_borders.1.x),
+                                    (world_borders.0.y, world_borders.1.y),
+                                    appetite / food.capacity,
+                                )
+                        }
+                        None => cfg.eating_speed,
+                    };
+                    if eaten > 0.0 {
+                        a.energy += eaten;
+                        if a.energy > MAX_ENERGY {
+                            a.energy = MAX_ENERGY;
+                        }
+                        has_eaten = true;
                     }
-                    has_eaten = true;
                 }
             } else if let Action::Procreate(Some(e_partner)) = a.action {
                 if a.energy >= cfg.procreation_min_energy {
@@ -283,16 +293,13 @@ pub fn preprocess_prey(
                             if e < e_partner {
                                 spawn(
                                     commands,
-                                    meshes,
-                                    materials,
-                                    &prey_mesh,
-                                    prey_scale,
-                                    prey_bb,
+                                    visuals,
                                     AgentType::Prey,
                                     a.location,
                                     a.direction,
                                     cfg.life,
                                 );
+                                metrics.births += 1;
                             }
                             has_procreated = true;
                         }
@@ -343,6 +350,7 @@ pub fn preprocess_prey(
                     corpses,
                     preys,
                     predators,
+                    grid,
                     &config.0,
                 ),
                 hearing: cast_rays_hearing(
@@ -357,6 +365,7 @@ pub fn preprocess_prey(
                     corpses,
                     preys,
                     predators,
+                    grid,
                     &config.0,
                 ),
             };
@@ -373,17 +382,31 @@ pub fn preprocess_prey(
             *killed > 0,
             cfg,
         );
-        if *killed == 0 && a.energy <= 0.0 {
+        metrics.add_reward(reward);
+        if has_eaten {
+            metrics.eaten += 1;
+        }
+        if *killed > 0 {
+            metrics.killed += 1;
+            metrics.end_episode(a.age);
+        } else if a.energy <= 0.0 {
             commands.entity(e).despawn_recursive();
+            metrics.starvations += 1;
+            metrics.end_episode(a.age);
         }
 
         if let Some(previous_state) = a.previous_state.as_ref() {
-            buf.buffer.add(Transition {
-                state: previous_state.clone(),
-                action: a.action,
-                reward,
-                next_state: a.state.as_ref().unwrap().clone(),
-            });
+            buf.buffer.push(
+                e,
+                Transition {
+                    state: previous_state.clone(),
+                    action: a.action,
+                    reward,
+                    next_state: a.state.as_ref().unwrap().clone(),
+                    done: *killed > 0 || a.energy <= 0.0,
+                    steps: 1,
+                },
+            );
         }
     }
 }
@@ -430,7 +453,7 @@ fn calculate_rewards(
             }
             _ => {}
         }
-        if d.food {
+        if d.food > 0.0 {
             num_food += 1;
         }
     }
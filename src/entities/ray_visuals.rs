@@ -0,0 +1,137 @@
+use bevy::{pbr::NotShadowCaster, prelude::*};
+use bevy_mod_picking::Selection;
+
+use crate::config::ConfigRes;
+
+use super::{raycast::RayDetection, Agent, AgentType};
+
+const RAY_WIDTH: f32 = 0.05;
+const MARKER_SIZE: f32 = 0.3;
+const VISION_HEIGHT: f32 = 0.5;
+const HEARING_HEIGHT: f32 = 0.25;
+
+/**
+ * Marks the ray segments of the selected agent, which are redrawn every frame
+ */
+#[derive(Component)]
+pub struct RayVisual {}
+
+/**
+ * Unlit materials of the rays, by `Detection` index, and of the end markers, by `EnvType` index
+ */
+#[derive(Resource)]
+pub struct RayVisuals {
+    mesh: Handle<Mesh>,
+    detection: Vec<Handle<StandardMaterial>>,
+    env: Vec<Handle<StandardMaterial>>,
+}
+impl FromWorld for RayVisuals {
+    fn from_world(world: &mut World) -> Self {
+        let mesh = world
+            .resource_mut::<Assets<Mesh>>()
+            .add(Mesh::from(shape::Cube { size: 1.0 }));
+        let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+        let mut unlit = |color: Color| {
+            materials.add(StandardMaterial {
+                base_color: color,
+                unlit: true,
+                ..default()
+            })
+        };
+        // Same order as `Detection::get_index`
+        let detection = [
+            Color::rgb(0.2, 1.0, 0.2), // PreyAlive
+            Color::rgb(0.6, 0.4, 0.2), // PreyDead
+            Color::rgb(1.0, 0.2, 0.2), // Predator
+            Color::rgb(0.2, 0.6, 1.0), // Wall
+            Color::rgb(0.7, 0.7, 0.7), // None
+        ]
+        .map(&mut unlit)
+        .to_vec();
+        // Same order as `EnvType::get_index`
+        let env = [
+            Color::rgb(0.6, 0.9, 0.3), // Meadow
+            Color::rgb(0.0, 0.4, 0.1), // Forest
+            Color::rgb(1.0, 0.9, 0.0), // Food
+            Color::rgb(0.0, 0.2, 0.9), // Water
+            Color::rgb(0.1, 0.1, 0.1), // Outside
+        ]
+        .map(&mut unlit)
+        .to_vec();
+        Self {
+            mesh,
+            detection,
+            env,
+        }
+    }
+}
+
+fn segment_transform(start: Vec3, direction: Vec3, length: f32) -> Transform {
+    Transform::from_translation(start + direction * length / 2.0)
+        .with_rotation(Quat::from_rotation_arc(Vec3::X, direction))
+        .with_scale(Vec3::new(length, RAY_WIDTH, RAY_WIDTH))
+}
+
+/**
+ * Draws the sight and hearing rays of the selected agent. Rays are coloured by what they detected
+ * and sight rays end with a marker coloured by the environment type they saw.
+ */
+pub fn render_rays(
+    mut commands: Commands,
+    agents: Query<(&Agent, &Selection)>,
+    rays: Query<Entity, With<RayVisual>>,
+    visuals: Res<RayVisuals>,
+    config: Res<ConfigRes>,
+) {
+    for entity in &rays {
+        commands.entity(entity).despawn();
+    }
+    let Some(agent) = agents
+        .iter()
+        .find_map(|(agent, sel)| sel.selected().then_some(agent))
+    else {
+        return;
+    };
+    let Some(state) = &agent.state else {
+        return;
+    };
+    let cfg = match agent.agent_type {
+        AgentType::Prey => &config.0.prey,
+        AgentType::Predator => &config.0.predator,
+    };
+
+    let mut spawn_ray = |ray: &RayDetection, height: f32, range: f32, vision: bool| {
+        let start = Vec3::new(state.location.x, height, state.location.y);
+        let direction = Vec3::new(ray.direction.x, 0.0, ray.direction.y);
+        let length = if ray.is_none() { range } else { ray.distance };
+        commands.spawn((
+            PbrBundle {
+                mesh: visuals.mesh.clone(),
+                material: visuals.detection[ray.detection.get_index()].clone(),
+                transform: segment_transform(start, direction, length),
+                ..default()
+            },
+            NotShadowCaster,
+            RayVisual {},
+        ));
+        if vision {
+            commands.spawn((
+                PbrBundle {
+                    mesh: visuals.mesh.clone(),
+                    material: visuals.env[ray.env.get_index()].clone(),
+                    transform: Transform::from_translation(start + direction * length)
+                        .with_scale(Vec3::splat(MARKER_SIZE)),
+                    ..default()
+                },
+                NotShadowCaster,
+                RayVisual {},
+            ));
+        }
+    };
+    for ray in &state.sight {
+        spawn_ray(ray, VISION_HEIGHT, cfg.vision_range, true);
+    }
+    for ray in &state.hearing {
+        spawn_ray(ray, HEARING_HEIGHT, cfg.hearing_range, false);
+    }
+}
@@ -2,16 +2,17 @@ use std::f32::consts::PI;
 
 use bevy::prelude::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     assets::MapAsset,
     entities::{bbox::get_bbox_corners, AgentType},
 };
 
-use super::{intersect::seg_box_intersect, map::EnvType};
+use super::{grid::AgentGrid, intersect::seg_box_intersect, map::EnvType};
 use crate::helpers::config_parser::Config;
 
-#[derive(Clone, Copy, PartialEq, Debug, Reflect, FromReflect)]
+#[derive(Clone, Copy, PartialEq, Debug, Reflect, FromReflect, Serialize, Deserialize)]
 pub enum Detection {
     PreyAlive(f32, f32), // Energy, Direction
     PreyDead(f32),
@@ -31,11 +32,11 @@ impl Detection {
     }
 }
 
-#[derive(Clone, Debug, Reflect, FromReflect)]
+#[derive(Clone, Debug, Reflect, FromReflect, Serialize, Deserialize)]
 pub struct RayDetection {
     pub distance: f32,
     pub detection: Detection,
-    pub food: bool,
+    pub food: f32,    // Share of the food left on the nearest food cell seen, 0 if none
     pub env: EnvType, // vision only
     pub direction: Vec2,
 }
@@ -44,7 +45,7 @@ impl RayDetection {
         Self {
             distance: -1.0,
             detection: Detection::None,
-            food: false,
+            food: 0.0,
             env: curr_env,
             direction,
         }
@@ -65,6 +66,7 @@ fn cast_rays(
     corpses: &[(Entity, Vec2, f32, f32)],
     preys: &[(Entity, Vec2, f32, f32, u32)],
     predators: &[(Entity, Vec2, f32, f32)],
+    grid: &AgentGrid,
     config: &Config,
 ) -> Vec<RayDetection> {
     let curr_env = map.0.get_env_type(
@@ -78,7 +80,13 @@ fn cast_rays(
     // Agents
     rays.par_iter_mut().for_each(|ray| {
         let dir = ray.direction;
-        for (e, l, d, en) in corpses {
+        let ray_end = location + distance * dir;
+        for (e, l, d, en) in grid
+            .corpses
+            .along(location, ray_end)
+            .into_iter()
+            .map(|i| &corpses[i])
+        {
             if *e == selected {
                 continue;
             }
@@ -105,14 +113,19 @@ fn cast_rays(
                     *ray = RayDetection {
                         distance: dist,
                         detection: Detection::PreyDead(*en),
-                        food: t == AgentType::Predator,
+                        food: if t == AgentType::Predator { 1.0 } else { 0.0 },
                         env: curr_env,
                         direction: ray.direction,
                     };
                 }
             }
         }
-        for (e, l, d, en, _) in preys {
+        for (e, l, d, en, _) in grid
+            .preys
+            .along(location, ray_end)
+            .into_iter()
+            .map(|i| &preys[i])
+        {
             if *e == selected {
                 continue;
             }
@@ -138,14 +151,19 @@ fn cast_rays(
                     *ray = RayDetection {
                         distance: dist,
                         detection: Detection::PreyAlive(*en, *d),
-                        food: t == AgentType::Predator,
+                        food: if t == AgentType::Predator { 1.0 } else { 0.0 },
                         env: curr_env,
                         direction: ray.direction,
                     };
                 }
             }
         }
-        for (e, l, d, en) in predators {
+        for (e, l, d, en) in grid
+            .predators
+            .along(location, ray_end)
+            .into_iter()
+            .map(|i| &predators[i])
+        {
             if *e == selected {
                 continue;
             }
@@ -175,7 +193,7 @@ fn cast_rays(
                     *ray = RayDetection {
                         distance: dist,
                         detection: Detection::Predator(*en, *d),
-                        food: false,
+                        food: 0.0,
                         env: curr_env,
                         direction: ray.direction,
                     };
@@ -203,7 +221,7 @@ fn cast_rays(
             *ray = RayDetection {
                 distance: (ray_end - location).length(),
                 detection: Detection::Wall,
-                food: false,
+                food: 0.0,
                 env: curr_env,
                 direction: ray.direction,
             };
@@ -223,8 +241,20 @@ fn cast_rays(
                     break;
                 }
             }
-            if env == EnvType::Food && t == AgentType::Prey {
-                ray.food = true;
+            // Eaten cells are seen as food again once some of it has grown back
+            let food = if env == EnvType::Food && t == AgentType::Prey {
+                map.0.get_food(
+                    ray_end,
+                    (world_borders.0.x, world_borders.1.x),
+                    (world_borders.0.y, world_borders.1.y),
+                )
+            } else {
+                0.0
+            };
+            if food > 0.0 {
+                if ray.food == 0.0 {
+                    ray.food = food;
+                }
                 if ray.distance < 0.0 {
                     ray.distance = dist;
                 }
@@ -250,6 +280,7 @@ pub fn cast_rays_vision(
     corpses: &[(Entity, Vec2, f32, f32)],
     preys: &[(Entity, Vec2, f32, f32, u32)],
     predators: &[(Entity, Vec2, f32, f32)],
+    grid: &AgentGrid,
     config: &Config,
 ) -> Vec<RayDetection> {
     let mut directions = Vec::with_capacity(num_rays);
@@ -275,6 +306,7 @@ pub fn cast_rays_vision(
         corpses,
         preys,
         predators,
+        grid,
         config,
     )
 }
@@ -291,6 +323,7 @@ pub fn cast_rays_hearing(
     corpses: &[(Entity, Vec2, f32, f32)],
     preys: &[(Entity, Vec2, f32, f32, u32)],
     predators: &[(Entity, Vec2, f32, f32)],
+    grid: &AgentGrid,
     config: &Config,
 ) -> Vec<RayDetection> {
     let mut directions = Vec::with_capacity(num_rays);
@@ -313,6 +346,7 @@ pub fn cast_rays_hearing(
         corpses,
         preys,
         predators,
+        grid,
         config,
     )
 }
@@ -4,68 +4,148 @@ use bevy::{pbr::NotShadowCaster, prelude::*};
 use bevy_mod_picking::{Highlighting, PickableBundle};
 
 use rand::Rng;
+use rand_chacha::ChaCha12Rng;
 use rand_distr::{Distribution, UnitCircle};
 
+use crate::{config::ConfigRes, helpers::config_parser::AgentConfig};
+
 use super::{Agent, AgentType};
 
+pub struct AgentVisual {
+    scene: Handle<Scene>,
+    scale: f32,
+    mesh: Handle<Mesh>,
+    initial: Handle<StandardMaterial>,
+    hovered: Handle<StandardMaterial>,
+    selected: Handle<StandardMaterial>,
+}
+
+/**
+ * Meshes, materials and glTF scenes of the agents. Only present when rendering.
+ */
+#[derive(Resource)]
+pub struct AgentVisuals {
+    prey: AgentVisual,
+    predator: AgentVisual,
+}
+impl AgentVisuals {
+    pub fn get(&self, t: AgentType) -> &AgentVisual {
+        match t {
+            AgentType::Prey => &self.prey,
+            AgentType::Predator => &self.predator,
+        }
+    }
+}
+impl FromWorld for AgentVisuals {
+    fn from_world(world: &mut World) -> Self {
+        let config = world.resource::<ConfigRes>();
+        let prey_cfg = (config.0.prey.size, bbox_shape(&config.0.prey));
+        let predator_cfg = (config.0.predator.size, bbox_shape(&config.0.predator));
+
+        let assets = world.resource::<AssetServer>();
+        let prey_scene = assets.load("models/deer.glb#Scene0");
+        let predator_scene = assets.load("models/wolf.glb#Scene0");
+
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        let prey_mesh = meshes.add(Mesh::from(prey_cfg.1));
+        let predator_mesh = meshes.add(Mesh::from(predator_cfg.1));
+
+        let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+        let mut visual = |scene, scale, mesh| AgentVisual {
+            scene,
+            scale,
+            mesh,
+            initial: materials.add(Color::rgba(0.5, 1.0, 0.5, 0.1).into()),
+            hovered: materials.add(Color::rgba(0.5, 0.5, 1.0, 0.1).into()),
+            selected: materials.add(Color::rgba(0.5, 0.5, 0.5, 0.2).into()),
+        };
+        Self {
+            prey: visual(prey_scene, prey_cfg.0, prey_mesh),
+            predator: visual(predator_scene, predator_cfg.0, predator_mesh),
+        }
+    }
+}
+
+fn bbox_shape(cfg: &AgentConfig) -> shape::Box {
+    shape::Box {
+        min_x: -cfg.size / 2.0,
+        max_x: cfg.size / 2.0,
+        min_y: 0.0,
+        max_y: cfg.size * cfg.hl_ratio,
+        min_z: -cfg.size * cfg.wl_ratio / 2.0,
+        max_z: cfg.size * cfg.wl_ratio / 2.0,
+    }
+}
+
 pub fn spawn(
     commands: &mut Commands,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    scene: &Handle<Scene>,
-    scale: f32,
-    bb: shape::Box,
+    visuals: Option<&AgentVisuals>,
     t: AgentType,
     loc: Vec2,
     direction: f32,
     life: usize,
+) -> Entity {
+    let e = commands.spawn_empty().id();
+    insert_agent(commands, visuals, e, Agent::new(t, loc, direction, life));
+    e
+}
+
+/**
+ * Turns an empty entity into an agent, e.g. one restored from a checkpoint
+ */
+pub fn insert_agent(
+    commands: &mut Commands,
+    visuals: Option<&AgentVisuals>,
+    e: Entity,
+    agent: Agent,
 ) {
+    let Some(visuals) = visuals else {
+        commands.entity(e).insert(agent);
+        return;
+    };
+    let visual = visuals.get(agent.agent_type);
     commands
-        .spawn((
+        .entity(e)
+        .insert((
             PbrBundle {
-                mesh: meshes.add(Mesh::from(bb)),
-                material: materials.add(Color::rgba(0.5, 1.0, 0.5, 0.1).into()),
-                transform: Transform::from_xyz(loc.x, 0.0, loc.y)
-                    .with_rotation(Quat::from_rotation_y(direction)),
+                mesh: visual.mesh.clone(),
+                material: visual.initial.clone(),
+                transform: Transform::from_xyz(agent.location.x, 0.0, agent.location.y)
+                    .with_rotation(Quat::from_rotation_y(agent.direction)),
                 visibility: Visibility::Visible,
                 ..default()
             },
             NotShadowCaster,
-            Agent::new(t, loc, direction, life),
+            agent,
         ))
         .with_children(|parent| {
             parent.spawn(SceneBundle {
-                scene: scene.clone(),
-                transform: Transform::from_xyz(0.0, 0.0, 0.0)
-                    .with_scale(Vec3::new(scale, scale, scale)),
+                scene: visual.scene.clone(),
+                transform: Transform::from_xyz(0.0, 0.0, 0.0).with_scale(Vec3::splat(visual.scale)),
                 visibility: Visibility::Visible,
                 ..default()
             });
         })
         .insert(PickableBundle::default())
         .insert(Highlighting {
-            initial: materials.add(Color::rgba(0.5, 1.0, 0.5, 0.1).into()),
-            hovered: Some(materials.add(Color::rgba(0.5, 0.5, 1.0, 0.1).into())),
+            initial: visual.initial.clone(),
+            hovered: Some(visual.hovered.clone()),
             pressed: None,
-            selected: Some(materials.add(Color::rgba(0.5, 0.5, 0.5, 0.2).into())),
+            selected: Some(visual.selected.clone()),
         });
 }
 
 pub fn batch_spawn(
     commands: &mut Commands,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
+    visuals: Option<&AgentVisuals>,
     world_size: Vec2,
-    scene: &Handle<Scene>,
-    scale: f32,
-    bb: shape::Box,
     t: AgentType,
     count: u32,
     batch_count: u32,
     batch_radius: f32,
     life: usize,
+    rng: &mut ChaCha12Rng,
 ) {
-    let mut rng = rand::thread_rng();
     let mut to_spawn = count;
     while to_spawn > 0 {
         let x = rng.gen::<f32>() * world_size.x;
@@ -75,11 +155,7 @@ pub fn batch_spawn(
         let direction = 0.0;
         spawn(
             commands,
-            meshes,
-            materials,
-            scene,
-            scale,
-            bb,
+            visuals,
             t,
             Vec2::new(x, y) - world_size / 2.0,
             direction,
@@ -89,7 +165,7 @@ pub fn batch_spawn(
         if to_spawn > 0 {
             let batch_size = rng.gen_range(0..=u32::min(batch_count, to_spawn));
             for _ in 0..batch_size {
-                let [bx, by]: [f32; 2] = UnitCircle.sample(&mut rng);
+                let [bx, by]: [f32; 2] = UnitCircle.sample(rng);
                 let bx = bx * batch_radius + x;
                 let by = by * batch_radius + y;
                 if bx < 0.0 || bx >= world_size.x || by < 0.0 || by >= world_size.y {
@@ -98,11 +174,7 @@ pub fn batch_spawn(
                 let bdirection = rng.gen::<f32>() * PI;
                 spawn(
                     commands,
-                    meshes,
-                    materials,
-                    scene,
-                    scale,
-                    bb,
+                    visuals,
                     t,
                     Vec2::new(bx, by) - world_size / 2.0,
                     bdirection,
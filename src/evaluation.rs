@@ -0,0 +1,237 @@
+use bevy::prelude::*;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    entities::{Agent, AgentType},
+    helpers::config_parser::Config,
+    metrics::{Metrics, SpeciesMetrics},
+    rl::model_helpers::timestamp,
+};
+
+/**
+ * Statistics recorded for every episode, in the order of the summary
+ */
+pub const STATISTICS: [&str; 11] = [
+    "length",
+    "prey_survival_time",
+    "predator_survival_time",
+    "kills_per_predator",
+    "prey_births",
+    "predator_births",
+    "prey_eaten",
+    "predator_eaten",
+    "prey_final_population",
+    "predator_final_population",
+    "predator_score",
+];
+
+/**
+ * Statistics of finished evaluation episodes. Present only in evaluation mode.
+ */
+#[derive(Resource)]
+pub struct Evaluation {
+    pub episodes: usize,
+    pub episode_length: usize,
+    pub frame: usize,
+    prey_count: usize, // Agents at the start of an episode
+    predator_count: usize,
+    results: Vec<Vec<(&'static str, f32)>>,
+    output: String,
+}
+impl Evaluation {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            episodes: config.eval.episodes,
+            episode_length: config.eval.episode_length,
+            frame: 0,
+            prey_count: config.prey.count as usize,
+            predator_count: config.predator.count as usize,
+            results: Vec::new(),
+            output: format!("{}/eval_{}.json", config.rl.save_path, timestamp()),
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.results.len() >= self.episodes
+    }
+    /**
+     * Forgets the finished episodes, so the same number can be played again
+     */
+    pub fn restart(&mut self) {
+        self.results.clear();
+        self.frame = 0;
+    }
+    /**
+     * Records an episode from the events collected in `metrics` and the agents at its end.
+     * Survival times include the ages of agents that are still alive.
+     *
+     * The predator score is 1 if the prey died out, 0 if the predators did and otherwise the share
+     * of prey lives ended by predators.
+     */
+    pub fn end_episode<'a>(&mut self, metrics: &Metrics, agents: impl Iterator<Item = &'a Agent>) {
+        let mut alive = [(0usize, 0usize); 2]; // Count and age sum of living agents per species
+        for agent in agents.filter(|a| a.alive) {
+            let species = &mut alive[agent.agent_type as usize];
+            species.0 += 1;
+            species.1 += agent.age;
+        }
+        let survival = |m: &SpeciesMetrics, (count, ages): (usize, usize)| {
+            let lives = m.episodes + count;
+            if lives > 0 {
+                (m.episode_length_sum + ages) as f32 / lives as f32
+            } else {
+                0.0
+            }
+        };
+        let predators = self.predator_count + metrics.predator.births;
+        let (prey, predator) = (&metrics.prey, &metrics.predator);
+        let predator_score = match (
+            alive[AgentType::Prey as usize].0,
+            alive[AgentType::Predator as usize].0,
+        ) {
+            (0, 0) => 0.5,
+            (0, _) => 1.0,
+            (_, 0) => 0.0,
+            _ => prey.killed as f32 / (self.prey_count + prey.births) as f32,
+        };
+        let values = [
+            self.frame as f32,
+            survival(prey, alive[AgentType::Prey as usize]),
+            survival(predator, alive[AgentType::Predator as usize]),
+            prey.killed as f32 / predators as f32,
+            prey.births as f32,
+            predator.births as f32,
+            prey.eaten as f32,
+            predator.eaten as f32,
+            alive[AgentType::Prey as usize].0 as f32,
+            alive[AgentType::Predator as usize].0 as f32,
+            predator_score,
+        ];
+        self.results
+            .push(STATISTICS.into_iter().zip(values).collect());
+        self.frame = 0;
+        println!(
+            "Evaluation episode {}/{} finished",
+            self.results.len(),
+            self.episodes
+        );
+    }
+    /**
+     * Mean, standard deviation, minimum and maximum of every statistic over the episodes
+     */
+    pub fn summary(&self) -> Vec<(&'static str, [f32; 4])> {
+        let Some(first) = self.results.first() else {
+            return Vec::new();
+        };
+        (0..first.len())
+            .map(|i| {
+                let values = self.results.iter().map(|r| r[i].1).collect::<Vec<_>>();
+                let n = values.len() as f32;
+                let mean = values.iter().sum::<f32>() / n;
+                let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+                let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+                let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                (first[i].0, [mean, std, min, max])
+            })
+            .collect()
+    }
+    /**
+     * Prints the summary as a table and writes the episodes and the summary to
+     * `eval_<timestamp>.json` in the save path
+     */
+    pub fn report(&self) {
+        let summary = self.summary();
+        println!(
+            "{:<28}{:>12}{:>12}{:>12}{:>12}",
+            format!("{} episodes", self.results.len()),
+            "mean",
+            "std",
+            "min",
+            "max"
+        );
+        for (name, [mean, std, min, max]) in &summary {
+            println!("{name:<28}{mean:>12.3}{std:>12.3}{min:>12.3}{max:>12.3}");
+        }
+
+        let episodes = self
+            .results
+            .iter()
+            .map(|r| Value::Object(r.iter().map(|(k, v)| (k.to_string(), json!(v))).collect()))
+            .collect::<Vec<_>>();
+        let summary = summary
+            .into_iter()
+            .map(|(name, [mean, std, min, max])| {
+                (
+                    name.to_string(),
+                    json!({"mean": mean, "std": std, "min": min, "max": max}),
+                )
+            })
+            .collect::<Map<_, _>>();
+        let report = json!({"episodes": episodes, "summary": summary});
+        std::fs::write(&self.output, report.to_string()).expect("Could not write evaluation");
+        println!("Evaluation written to {}", self.output);
+    }
+}
+
+#[cfg(test)]
+mod evaluation_tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut evaluation = Evaluation {
+            episodes: 2,
+            episode_length: 100,
+            frame: 100,
+            prey_count: 4,
+            predator_count: 2,
+            results: Vec::new(),
+            output: String::new(),
+        };
+        let mut metrics = Metrics::default();
+        metrics.prey.killed = 3;
+        metrics.predator.births = 1;
+        metrics.prey.end_episode(10);
+        let mut agent = Agent::new(AgentType::Prey, Vec2::ZERO, 0.0, 100);
+        agent.age = 30;
+        evaluation.end_episode(&metrics, [&agent].into_iter());
+        evaluation.frame = 50;
+        evaluation.end_episode(&Metrics::default(), std::iter::empty());
+        assert!(evaluation.is_finished());
+
+        let summary = evaluation.summary();
+        let get = |name| summary.iter().find(|(n, _)| *n == name).unwrap().1;
+        assert_eq!(get("length"), [75.0, 25.0, 50.0, 100.0]);
+        assert_eq!(get("prey_survival_time")[3], 20.0);
+        assert_eq!(get("kills_per_predator")[3], 1.0);
+        assert_eq!(get("prey_final_population")[0], 0.5);
+    }
+
+    #[test]
+    fn test_predator_score() {
+        let mut evaluation = Evaluation {
+            episodes: 4,
+            episode_length: 100,
+            frame: 100,
+            prey_count: 4,
+            predator_count: 2,
+            results: Vec::new(),
+            output: String::new(),
+        };
+        let prey = Agent::new(AgentType::Prey, Vec2::ZERO, 0.0, 100);
+        let predator = Agent::new(AgentType::Predator, Vec2::ZERO, 0.0, 100);
+        let mut metrics = Metrics::default();
+        metrics.prey.killed = 3;
+        metrics.prey.births = 2;
+        evaluation.end_episode(&metrics, [&prey, &predator].into_iter());
+        evaluation.end_episode(&metrics, [&predator].into_iter());
+        evaluation.end_episode(&metrics, [&prey].into_iter());
+        evaluation.end_episode(&metrics, std::iter::empty());
+
+        let scores = evaluation
+            .results
+            .iter()
+            .map(|r| r.iter().find(|(n, _)| *n == "predator_score").unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(scores, vec![0.5, 1.0, 0.0, 0.5]);
+    }
+}
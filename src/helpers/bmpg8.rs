@@ -1,42 +1,227 @@
-fn get_le_u32(bytes: [u8; 4]) -> u32 {
-    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+use std::fmt;
+
+const FILE_HEADER_SIZE: usize = 14;
+const CORE_HEADER_SIZE: usize = 12;
+const INFO_HEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BGRA_MASKS: [u32; 3] = [0x00FF_0000, 0x0000_FF00, 0x0000_00FF];
+
+/**
+ * Why a file could not be read as a BMP map
+ */
+#[derive(Debug, PartialEq)]
+pub enum BmpError {
+    NotBmp,
+    Truncated { needed: usize, len: usize },
+    UnsupportedHeader(usize),
+    UnsupportedBitDepth(u16),
+    UnsupportedCompression(u32),
+    InvalidSize { width: i64, height: i64 },
+    InvalidValue { value: u8, row: usize, col: usize },
+}
+impl fmt::Display for BmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotBmp => write!(f, "not a BMP file"),
+            Self::Truncated { needed, len } => {
+                write!(f, "BMP file is truncated: needs {needed} bytes, has {len}")
+            }
+            Self::UnsupportedHeader(size) => write!(f, "unsupported BMP header of {size} bytes"),
+            Self::UnsupportedBitDepth(bits) => write!(
+                f,
+                "unsupported BMP bit depth {bits}, expected 8, 24 or 32 bits per pixel"
+            ),
+            Self::UnsupportedCompression(compression) => write!(
+                f,
+                "unsupported BMP compression {compression}, only uncompressed images are read"
+            ),
+            Self::InvalidSize { width, height } => {
+                write!(f, "invalid BMP size {width}x{height}")
+            }
+            Self::InvalidValue { value, row, col } => write!(
+                f,
+                "invalid environment type {value} at row {row}, column {col}, expected 0 to 3"
+            ),
+        }
+    }
+}
+impl std::error::Error for BmpError {}
+
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], BmpError> {
+    data.get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(BmpError::Truncated {
+            needed: offset + N,
+            len: data.len(),
+        })
 }
 
-fn get_le_u16(bytes: [u8; 2]) -> u32 {
-    (bytes[0] as u32) | (bytes[1] as u32) << 8
+fn get_le_u32(data: &[u8], offset: usize) -> Result<u32, BmpError> {
+    bytes(data, offset).map(u32::from_le_bytes)
 }
 
+fn get_le_i32(data: &[u8], offset: usize) -> Result<i32, BmpError> {
+    bytes(data, offset).map(i32::from_le_bytes)
+}
+
+fn get_le_u16(data: &[u8], offset: usize) -> Result<u16, BmpError> {
+    bytes(data, offset).map(u16::from_le_bytes)
+}
+
+/**
+ * An uncompressed BMP with rows from top to bottom. `data` holds the palette indices of 8-bit
+ * images and RGB triples of 24 and 32-bit images.
+ */
 pub struct BmpImg {
     pub rows: usize,
     pub cols: usize,
+    pub bits: u16,
     pub data: Vec<u8>,
 }
 impl BmpImg {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(
-            !(bytes[0] != b'B' || bytes[1] != b'M' && bytes[1] != b'A'),
-            "Not a supported BMP format"
-        );
-
-        let offset = get_le_u32(bytes[10..14].try_into().expect("BMP header error")) as usize;
-        let header_size = get_le_u32(bytes[14..18].try_into().expect("BMP header error")) as usize;
-        let cols = if header_size == 12 {
-            get_le_u16(bytes[18..20].try_into().expect("BMP DIB header error")) as usize
-        } else {
-            get_le_u32(bytes[18..22].try_into().expect("BMP DIB header error")) as usize
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BmpError> {
+        if !data.starts_with(b"BM") && !data.starts_with(b"BA") {
+            return Err(BmpError::NotBmp);
+        }
+        let offset = get_le_u32(data, 10)? as usize;
+        let header_size = get_le_u32(data, FILE_HEADER_SIZE)? as usize;
+        let header = FILE_HEADER_SIZE + 4;
+        let (width, height, bits, compression) = match header_size {
+            CORE_HEADER_SIZE => (
+                get_le_u16(data, header)? as i64,
+                get_le_u16(data, header + 2)? as i64,
+                get_le_u16(data, header + 6)?,
+                BI_RGB,
+            ),
+            size if size >= INFO_HEADER_SIZE => (
+                get_le_i32(data, header)? as i64,
+                get_le_i32(data, header + 4)? as i64,
+                get_le_u16(data, header + 10)?,
+                get_le_u32(data, header + 12)?,
+            ),
+            size => return Err(BmpError::UnsupportedHeader(size)),
         };
-        let rows = if header_size == 12 {
-            get_le_u16(bytes[20..22].try_into().expect("BMP DIB header error")) as usize
-        } else {
-            get_le_u32(bytes[22..26].try_into().expect("BMP DIB header error")) as usize
+        if ![8, 24, 32].contains(&bits) {
+            return Err(BmpError::UnsupportedBitDepth(bits));
+        }
+        // 32-bit images may store their (usual) channel masks after the header
+        let masks = || -> Result<[u32; 3], BmpError> {
+            let masks = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+            Ok([
+                get_le_u32(data, masks)?,
+                get_le_u32(data, masks + 4)?,
+                get_le_u32(data, masks + 8)?,
+            ])
         };
-        let row_size = ((8 * cols + 31) / 32) * 4;
-        let size = row_size * rows;
-        let mut data = vec![0; size];
+        let bgra = bits == 32 && compression == BI_BITFIELDS && masks()? == BGRA_MASKS;
+        if compression != BI_RGB && !bgra {
+            return Err(BmpError::UnsupportedCompression(compression));
+        }
+        // Negative heights are stored top-down
+        if width <= 0 || height == 0 {
+            return Err(BmpError::InvalidSize { width, height });
+        }
+        let (rows, cols) = (height.unsigned_abs() as usize, width as usize);
+        let bytes_per_pixel = bits as usize / 8;
+        // Sizes come from the header, so they may not fit in memory
+        let size_error = || BmpError::InvalidSize { width, height };
+        let row_size = (bits as usize)
+            .checked_mul(cols)
+            .ok_or_else(size_error)?
+            .div_ceil(32)
+            * 4;
+        let needed = row_size
+            .checked_mul(rows)
+            .and_then(|size| size.checked_add(offset))
+            .ok_or_else(size_error)?;
+        if data.len() < needed {
+            return Err(BmpError::Truncated {
+                needed,
+                len: data.len(),
+            });
+        }
+
+        let channels = if bits == 8 { 1 } else { 3 };
+        let mut pixels = Vec::with_capacity(rows * cols * channels);
         for i in 0..rows {
-            let row_offset = offset + (rows - i - 1) * row_size;
-            data[i * cols..(i + 1) * cols].copy_from_slice(&bytes[row_offset..row_offset + cols]);
+            let stored_row = if height > 0 { rows - i - 1 } else { i };
+            let row = &data[offset + stored_row * row_size..][..cols * bytes_per_pixel];
+            if bits == 8 {
+                pixels.extend_from_slice(row);
+            } else {
+                // Pixels are stored as BGR(A)
+                for pixel in row.chunks_exact(bytes_per_pixel) {
+                    pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                }
+            }
         }
-        Self { rows, cols, data }
+        Ok(Self {
+            rows,
+            cols,
+            bits,
+            data: pixels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod bmp_tests {
+    use super::*;
+
+    /**
+     * An uncompressed BMP with a 40 byte info header and no colour table
+     */
+    fn bmp(width: i32, height: i32, bits: u16, rows: &[&[u8]]) -> Vec<u8> {
+        let offset = 54u32;
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bits.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        for row in rows {
+            data.extend_from_slice(row);
+            data.resize(data.len() + (4 - row.len() % 4) % 4, 0);
+        }
+        data
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        // Bottom-up, so the first stored row is the last one
+        let img = BmpImg::from_bytes(&bmp(3, 2, 8, &[&[3, 2, 1], &[0, 1, 2]])).unwrap();
+        assert_eq!((img.rows, img.cols), (2, 3));
+        assert_eq!(img.data, vec![0, 1, 2, 3, 2, 1]);
+
+        let img = BmpImg::from_bytes(&bmp(1, -2, 24, &[&[1, 2, 3], &[4, 5, 6]])).unwrap();
+        assert_eq!(img.data, vec![3, 2, 1, 6, 5, 4]);
+
+        let img = BmpImg::from_bytes(&bmp(1, 1, 32, &[&[1, 2, 3, 255]])).unwrap();
+        assert_eq!(img.data, vec![3, 2, 1]);
+
+        let truncated = bmp(3, 2, 8, &[&[0, 1, 2]]);
+        assert!(matches!(
+            BmpImg::from_bytes(&truncated),
+            Err(BmpError::Truncated { .. })
+        ));
+        assert_eq!(
+            BmpImg::from_bytes(&bmp(1, 1, 4, &[&[0]])).err(),
+            Some(BmpError::UnsupportedBitDepth(4))
+        );
+        let huge = bmp(i32::MAX, i32::MIN, 32, &[]);
+        assert!(matches!(
+            BmpImg::from_bytes(&huge),
+            Err(BmpError::Truncated { .. } | BmpError::InvalidSize { .. })
+        ));
+        assert_eq!(BmpImg::from_bytes(b"PNG").err(), Some(BmpError::NotBmp));
+        assert!(matches!(
+            BmpImg::from_bytes(b"BM"),
+            Err(BmpError::Truncated { .. })
+        ));
     }
 }
@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+
+use toml::Value;
+
+const USAGE: &str = "Usage: test1 [options]
+    --config <path>     Config file, instead of config.toml in the working directory
+    --set <key>=<value> Overrides a config value, e.g. --set rl.learning_rate=0.001
+    --learn             Same as --set rl.learn=true
+    --headless          Same as --set rl.headless=true
+    --load <path>       Same as --set rl.load_path=<path>
+    --step <n>          Same as --set rl.load_step=<n>
+    --resume            Same as --set rl.checkpoints.resume=true
+    --seed <n>          Same as --set world.seed=<n>
+    --replay <file>     Plays back a recorded episode
+    --eval              Evaluates the models over [eval] episodes without learning
+    --prey <path>       Same as --set eval.prey_path=<path>
+    --predator <path>   Same as --set eval.predator_path=<path>
+    --tournament <dir>  Evaluates every prey model of a directory against every predator model
+    --sweep <file>      Trains and evaluates the runs of a sweep spec, then ranks them
+    --help              Prints this message";
+
+/**
+ * Command-line arguments. Flags are shorthands for overrides, which are applied to the parsed
+ * config file in the given order.
+ */
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub overrides: Vec<(String, Value)>,
+    pub replay: Option<String>,
+    pub evaluate: bool,
+    pub tournament: Option<String>,
+    pub sweep: Option<String>,
+}
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => parsed.config = Some(next_value(&mut args, &arg).into()),
+                "--set" => {
+                    let pair = next_value(&mut args, &arg);
+                    let (key, value) = pair
+                        .split_once('=')
+                        .unwrap_or_else(|| panic!("--set expects key=value, got {pair}"));
+                    parsed
+                        .overrides
+                        .push((key.trim().to_string(), parse_value(value.trim())));
+                }
+                "--learn" => parsed
+                    .overrides
+                    .push(("rl.learn".to_string(), Value::Boolean(true))),
+                "--headless" => parsed
+                    .overrides
+                    .push(("rl.headless".to_string(), Value::Boolean(true))),
+                "--load" => parsed.overrides.push((
+                    "rl.load_path".to_string(),
+                    Value::String(next_value(&mut args, &arg)),
+                )),
+                "--step" => {
+                    let step = next_value(&mut args, &arg)
+                        .parse::<u64>()
+                        .expect("--step expects an unsigned integer");
+                    parsed
+                        .overrides
+                        .push(("rl.load_step".to_string(), Value::Integer(step as i64)));
+                }
+                "--resume" => parsed
+                    .overrides
+                    .push(("rl.checkpoints.resume".to_string(), Value::Boolean(true))),
+                "--seed" => {
+                    let seed = next_value(&mut args, &arg)
+                        .parse::<u64>()
+                        .expect("--seed expects an unsigned integer");
+                    parsed
+                        .overrides
+                        .push(("world.seed".to_string(), Value::Integer(seed as i64)));
+                }
+                "--replay" => parsed.replay = Some(next_value(&mut args, &arg)),
+                "--eval" => parsed.evaluate = true,
+                "--prey" => parsed.overrides.push((
+                    "eval.prey_path".to_string(),
+                    Value::String(next_value(&mut args, &arg)),
+                )),
+                "--predator" => parsed.overrides.push((
+                    "eval.predator_path".to_string(),
+                    Value::String(next_value(&mut args, &arg)),
+                )),
+                "--tournament" => parsed.tournament = Some(next_value(&mut args, &arg)),
+                "--sweep" => parsed.sweep = Some(next_value(&mut args, &arg)),
+                "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => panic!("Unknown argument {arg}\n{USAGE}"),
+            }
+        }
+        parsed
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> String {
+    args.next()
+        .unwrap_or_else(|| panic!("{flag} expects a value\n{USAGE}"))
+}
+
+/**
+ * Parses a value as TOML (numbers, booleans, arrays, quoted strings), falling back to an unquoted
+ * string, so paths can be passed as is
+ */
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/**
+ * Sets a dotted key (e.g. `rl.learning_rate`) in the parsed config file. Only the last part of the
+ * key may be missing from the file.
+ */
+pub fn apply_override(config: &mut toml::Table, key: &str, value: Value) {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let last = parts.pop().expect("Empty config key");
+    let mut table = config;
+    for part in parts {
+        table = table
+            .get_mut(part)
+            .and_then(Value::as_table_mut)
+            .unwrap_or_else(|| panic!("Unknown config table {part} in {key}"));
+    }
+    if !table.contains_key(last) {
+        println!("Setting {key}, which is not in the config file");
+    }
+    table.insert(last.to_string(), value);
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let args = Args::parse(
+            [
+                "--set",
+                "rl.learning_rate=0.001",
+                "--set",
+                "world.map=maps/03",
+                "--learn",
+                "--set",
+                "rl.layers=[32, 32]",
+            ]
+            .into_iter()
+            .map(String::from),
+        );
+        let mut config: toml::Table =
+            toml::from_str("[world]\nmap = \"maps/02\"\n[rl]\nlearn = false\nlearning_rate = 0.1")
+                .unwrap();
+        for (key, value) in args.overrides {
+            apply_override(&mut config, &key, value);
+        }
+        assert_eq!(config["rl"]["learning_rate"].as_float(), Some(0.001));
+        assert_eq!(config["world"]["map"].as_str(), Some("maps/03"));
+        assert_eq!(config["rl"]["learn"].as_bool(), Some(true));
+        assert_eq!(config["rl"]["layers"].as_array().map(Vec::len), Some(2));
+    }
+}
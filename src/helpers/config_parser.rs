@@ -1,6 +1,11 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use toml::Value;
+
+use super::{cli::apply_override, map::Palette};
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -9,9 +14,10 @@ pub struct Config {
     pub rl: RLConfig,
     pub predator: AgentConfig,
     pub prey: AgentConfig,
+    pub eval: EvalConfig,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct WorldConfig {
     pub world_width: f32,
     pub world_height: f32,
@@ -20,6 +26,38 @@ pub struct WorldConfig {
     pub forrest_vision_multiplier: f32,
     pub batch_spawn_count: u32,
     pub batch_spawn_radius: f32,
+    pub grid_cell_size: f32,
+    pub seed: Option<u64>,
+    pub palette: Option<Palette>, // Colours of PNG maps
+    pub generator: Option<GeneratorConfig>,
+    pub food: Option<FoodConfig>, // Food cells never run out without it
+}
+
+/**
+ * Food cells hold `capacity` energy, which is eaten by prey and grows back by `regrowth` energy
+ * per frame
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FoodConfig {
+    pub capacity: f32,
+    pub regrowth: f32,
+}
+
+/**
+ * Generates a map of `rows` x `cols` cells instead of loading `map`. Coverages are shares of all
+ * cells. Without a seed the generator is seeded from the simulation's.
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct GeneratorConfig {
+    pub rows: usize,
+    pub cols: usize,
+    pub seed: Option<u64>,
+    pub forest: f32,
+    pub water: f32,
+    pub food: f32,
+    pub rivers: usize,
+    pub food_patches: usize,
+    pub regenerate: bool, // New map after every extinction
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,15 +68,28 @@ pub struct CameraConfig {
     pub scroll_sensitivity: f32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct RLConfig {
     pub learn: bool,
+    pub headless: bool,
+    pub prey_algorithm: Algorithm,
+    pub predator_algorithm: Algorithm,
     pub replay_buffer_size: usize,
+    pub prioritized_replay: bool,
+    pub priority_alpha: f32,
+    pub priority_beta: f32,
+    pub priority_beta_step: f32,
     pub layers: Vec<usize>,
+    pub dueling: bool,
+    pub double_dqn: bool,
+    pub recurrent: bool,
+    pub recurrent_size: usize,
+    pub sequence_length: usize,
     pub learning_rate: f32,
     pub eps_step: f32,
     pub eps_min: f32,
     pub discount: f32,
+    pub n_step: usize,
     pub sample_count: usize,
     pub batch_size: usize,
     pub frames_per_update: usize,
@@ -50,9 +101,61 @@ pub struct RLConfig {
     pub save_path: String,
     pub load_path: Option<String>,
     pub load_model_name: Option<String>,
+    pub load_step: Option<usize>,
+    pub metrics_format: Option<MetricsFormat>,
+    pub record: bool,
+    pub ppo: PPOConfig,
+    pub checkpoints: CheckpointConfig,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Dqn,
+    Ppo,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PPOConfig {
+    pub clip: f32,
+    pub gae_lambda: f32,
+    pub epochs: usize,
+    pub value_coef: f32,
+    pub entropy_coef: f32,
+}
+
+/**
+ * Evaluation runs (`--eval`). Models are loaded from the given checkpoints, or from `load_path`.
+ */
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EvalConfig {
+    pub episodes: usize,
+    pub episode_length: usize, // Frames, an episode also ends when a species dies out
+    pub prey_path: Option<String>,
+    pub predator_path: Option<String>,
+}
+
+/**
+ * Retention of the checkpoints in the save path. `keep_last = 0` keeps only the best ones, and
+ * all of them if `keep_best` is 0 as well.
+ */
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckpointConfig {
+    pub keep_last: usize,
+    pub keep_best: usize, // By the sum of both species' mean rewards
+    pub resume: bool,     // Also restore buffers, counters and the generator from load_path
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct AgentConfig {
     pub count: u32,
     pub size: f32,
@@ -83,7 +186,7 @@ pub struct AgentConfig {
     pub rewards: RewardsConfig,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct RewardsConfig {
     pub tick: f32,
     pub turn: f32,
@@ -97,16 +200,478 @@ pub struct RewardsConfig {
     pub detecting_food: f32,
 }
 
-pub fn read_config() -> Config {
-    let mut path = env::current_dir().unwrap();
-    path.push("config.toml");
-    if let Ok(config_file) = fs::read_to_string(path.clone()) {
-        toml::from_str(config_file.as_str()).expect("Unable to parse toml file")
-    } else {
-        path.pop();
-        path.pop();
-        path.push("config.toml");
-        let config_file = fs::read_to_string(path).expect("Unable to read config file");
-        toml::from_str(config_file.as_str()).expect("Unable to parse toml file")
+/**
+ * Reads the config from `path`, or from `config.toml` in the working directory or two levels up,
+ * and applies the overrides (dotted keys) before parsing it
+ */
+pub fn read_config(path: Option<&Path>, overrides: &[(String, Value)]) -> Config {
+    Value::Table(read_config_table(path, overrides))
+        .try_into()
+        .expect("Unable to parse toml file")
+}
+
+/**
+ * The config file with the overrides applied, before parsing
+ */
+pub fn read_config_table(path: Option<&Path>, overrides: &[(String, Value)]) -> toml::Table {
+    let config_file = match path {
+        Some(path) => fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Unable to read config file {}", path.display())),
+        None => {
+            let mut path = env::current_dir().unwrap();
+            path.push("config.toml");
+            if let Ok(config_file) = fs::read_to_string(path.clone()) {
+                config_file
+            } else {
+                path.pop();
+                path.pop();
+                path.push("config.toml");
+                fs::read_to_string(path).expect("Unable to read config file")
+            }
+        }
+    };
+    let mut table: toml::Table =
+        toml::from_str(config_file.as_str()).expect("Unable to parse toml file");
+    for (key, value) in overrides {
+        apply_override(&mut table, key, value.clone());
+    }
+    table
+}
+
+/**
+ * A config value which would break the simulation or training, with the dotted path of its field
+ */
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn check(errors: &mut Vec<ConfigError>, ok: bool, path: &str, field: &str, message: &str) {
+    if !ok {
+        errors.push(ConfigError {
+            path: format!("{path}.{field}"),
+            message: message.to_string(),
+        });
+    }
+}
+
+impl Config {
+    /**
+     * Checks all sections and returns every problem found, not just the first one
+     */
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        self.world.validate("world", &mut errors);
+        self.rl.validate("rl", &mut errors);
+        self.prey.validate("prey", &mut errors);
+        self.predator.validate("predator", &mut errors);
+        self.eval.validate("eval", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl WorldConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let e = errors;
+        check(
+            e,
+            self.world_width > 0.0,
+            path,
+            "world_width",
+            "must be positive",
+        );
+        check(
+            e,
+            self.world_height > 0.0,
+            path,
+            "world_height",
+            "must be positive",
+        );
+        check(e, !self.map.is_empty(), path, "map", "must not be empty");
+        check(
+            e,
+            self.water_multiplier >= 0.0,
+            path,
+            "water_multiplier",
+            "must not be negative",
+        );
+        check(
+            e,
+            self.forrest_vision_multiplier >= 0.0,
+            path,
+            "forrest_vision_multiplier",
+            "must not be negative",
+        );
+        check(
+            e,
+            self.batch_spawn_radius >= 0.0,
+            path,
+            "batch_spawn_radius",
+            "must not be negative",
+        );
+        check(
+            e,
+            self.grid_cell_size > 0.0,
+            path,
+            "grid_cell_size",
+            "must be positive",
+        );
+        if let Some(generator) = &self.generator {
+            let path = format!("{path}.generator");
+            check(e, generator.rows > 0, &path, "rows", "must be positive");
+            check(e, generator.cols > 0, &path, "cols", "must be positive");
+            for (field, value) in [
+                ("forest", generator.forest),
+                ("water", generator.water),
+                ("food", generator.food),
+            ] {
+                check(
+                    e,
+                    (0.0..=1.0).contains(&value),
+                    &path,
+                    field,
+                    "must be between 0 and 1",
+                );
+            }
+            check(
+                e,
+                generator.forest + generator.water + generator.food <= 1.0,
+                &path,
+                "forest",
+                "forest, water and food must cover at most the whole map",
+            );
+            check(
+                e,
+                generator.food == 0.0 || generator.food_patches > 0,
+                &path,
+                "food_patches",
+                "must be positive if there is food",
+            );
+        }
+        if let Some(food) = &self.food {
+            let path = format!("{path}.food");
+            check(
+                e,
+                food.capacity > 0.0,
+                &path,
+                "capacity",
+                "must be positive",
+            );
+            check(
+                e,
+                food.regrowth >= 0.0,
+                &path,
+                "regrowth",
+                "must not be negative",
+            );
+        }
+    }
+}
+
+impl EvalConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let e = errors;
+        check(e, self.episodes > 0, path, "episodes", "must be positive");
+        check(
+            e,
+            self.episode_length > 0,
+            path,
+            "episode_length",
+            "must be positive",
+        );
+        for (field, value) in [
+            ("prey_path", &self.prey_path),
+            ("predator_path", &self.predator_path),
+        ] {
+            if let Some(value) = value {
+                check(
+                    e,
+                    Path::new(value).is_dir(),
+                    path,
+                    field,
+                    &format!("{value} is not a directory"),
+                );
+            }
+        }
+    }
+}
+
+impl RLConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let e = errors;
+        check(
+            e,
+            self.replay_buffer_size > 0,
+            path,
+            "replay_buffer_size",
+            "must be positive",
+        );
+        check(
+            e,
+            (0.0..=1.0).contains(&self.priority_beta),
+            path,
+            "priority_beta",
+            "must be between 0 and 1",
+        );
+        check(
+            e,
+            self.priority_alpha >= 0.0,
+            path,
+            "priority_alpha",
+            "must not be negative",
+        );
+        check(
+            e,
+            self.layers.iter().all(|l| *l > 0),
+            path,
+            "layers",
+            "layer sizes must be positive",
+        );
+        if self.recurrent {
+            check(
+                e,
+                self.recurrent_size > 0,
+                path,
+                "recurrent_size",
+                "must be positive",
+            );
+            check(
+                e,
+                self.sequence_length > 0,
+                path,
+                "sequence_length",
+                "must be positive",
+            );
+        }
+        check(
+            e,
+            self.learning_rate > 0.0,
+            path,
+            "learning_rate",
+            "must be positive",
+        );
+        check(
+            e,
+            self.eps_step >= 0.0,
+            path,
+            "eps_step",
+            "must not be negative",
+        );
+        check(
+            e,
+            (0.0..=1.0).contains(&self.eps_min),
+            path,
+            "eps_min",
+            "must be between 0 and 1",
+        );
+        check(
+            e,
+            (0.0..=1.0).contains(&self.discount),
+            path,
+            "discount",
+            "must be between 0 and 1",
+        );
+        check(e, self.n_step > 0, path, "n_step", "must be positive");
+        check(
+            e,
+            self.batch_size > 0,
+            path,
+            "batch_size",
+            "must be positive",
+        );
+        check(
+            e,
+            self.batch_size <= self.sample_count,
+            path,
+            "batch_size",
+            &format!("must not exceed sample_count ({})", self.sample_count),
+        );
+        for (field, value) in [
+            ("frames_per_update", self.frames_per_update),
+            ("updates_per_target", self.updates_per_target),
+            ("updates_per_save", self.updates_per_save),
+            ("updates_per_reset", self.updates_per_reset),
+            ("updates_per_swap", self.updates_per_swap.unwrap_or(1)),
+        ] {
+            check(e, value > 0, path, field, "must be positive");
+        }
+        check(
+            e,
+            !self.save_path.is_empty(),
+            path,
+            "save_path",
+            "must not be empty",
+        );
+        if let Some(load_path) = &self.load_path {
+            check(
+                e,
+                Path::new(load_path).is_dir(),
+                path,
+                "load_path",
+                &format!("{load_path} is not a directory"),
+            );
+        }
+        check(
+            e,
+            !self.checkpoints.resume || self.load_path.is_some(),
+            &format!("{path}.checkpoints"),
+            "resume",
+            "requires load_path",
+        );
+        let path = format!("{path}.ppo");
+        check(e, self.ppo.clip > 0.0, &path, "clip", "must be positive");
+        check(
+            e,
+            (0.0..=1.0).contains(&self.ppo.gae_lambda),
+            &path,
+            "gae_lambda",
+            "must be between 0 and 1",
+        );
+        check(e, self.ppo.epochs > 0, &path, "epochs", "must be positive");
+    }
+}
+
+impl AgentConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        let e = &mut *errors;
+        // Episodes end as soon as a species dies out
+        check(e, self.count > 0, path, "count", "must be positive");
+        check(e, self.size > 0.0, path, "size", "must be positive");
+        check(e, self.wl_ratio > 0.0, path, "wl_ratio", "must be positive");
+        check(e, self.hl_ratio > 0.0, path, "hl_ratio", "must be positive");
+        check(
+            e,
+            self.walk_speed <= self.run_speed,
+            path,
+            "walk_speed",
+            &format!("must not exceed run_speed ({})", self.run_speed),
+        );
+        for (field, value) in [
+            ("walk_speed", self.walk_speed),
+            ("walk_acceleration", self.walk_acceleration),
+            ("run_acceleration", self.run_acceleration),
+            ("deceleration", self.deceleration),
+            ("turn_speed", self.turn_speed),
+            ("eating_speed", self.eating_speed),
+            ("procreation_min_energy", self.procreation_min_energy),
+            (
+                "procreation_attempt_energy_loss",
+                self.procreation_attempt_energy_loss,
+            ),
+            ("procreation_energy_loss", self.procreation_energy_loss),
+            ("tick_energy_loss", self.tick_energy_loss),
+            ("turn_energy_loss", self.turn_energy_loss),
+            ("walk_energy_loss", self.walk_energy_loss),
+            ("run_energy_loss", self.run_energy_loss),
+            ("attack_energy_loss", self.attack_energy_loss),
+        ] {
+            check(e, value >= 0.0, path, field, "must not be negative");
+        }
+        check(
+            e,
+            self.vision_range > 0.0,
+            path,
+            "vision_range",
+            "must be positive",
+        );
+        check(
+            e,
+            self.vision_fov >= 0.0,
+            path,
+            "vision_fov",
+            "must not be negative",
+        );
+        check(
+            e,
+            self.vision_rays > 0,
+            path,
+            "vision_rays",
+            "must be positive",
+        );
+        check(
+            e,
+            self.hearing_range > 0.0,
+            path,
+            "hearing_range",
+            "must be positive",
+        );
+        check(
+            e,
+            self.hearing_rays > 0,
+            path,
+            "hearing_rays",
+            "must be positive",
+        );
+        check(e, self.life > 0, path, "life", "must be positive");
+        self.rewards.validate(&format!("{path}.rewards"), errors);
+    }
+}
+
+impl RewardsConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<ConfigError>) {
+        for (field, value) in [
+            ("tick", self.tick),
+            ("turn", self.turn),
+            ("walk", self.walk),
+            ("run", self.run),
+            ("eat", self.eat),
+            ("procreation", self.procreation),
+            ("death", self.death),
+            ("detecting_prey", self.detecting_prey),
+            ("detecting_predator", self.detecting_predator),
+            ("detecting_food", self.detecting_food),
+        ] {
+            check(
+                errors,
+                value.is_finite(),
+                path,
+                field,
+                "must be a finite number",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let overrides = [
+            ("rl.replay_buffer_size", Value::Integer(0)),
+            ("rl.batch_size", Value::Integer(1000)),
+            ("rl.load_path", Value::String("no/such/dir".to_string())),
+            ("prey.vision_rays", Value::Integer(0)),
+            ("predator.rewards.eat", Value::Float(f64::NAN)),
+        ]
+        .map(|(k, v)| (k.to_string(), v));
+        let config = read_config(Some(Path::new("config.toml")), &overrides);
+        let paths = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "rl.replay_buffer_size",
+                "rl.batch_size",
+                "rl.load_path",
+                "prey.vision_rays",
+                "predator.rewards.eat",
+            ]
+        );
     }
 }
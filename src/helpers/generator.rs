@@ -0,0 +1,230 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+
+use super::{
+    config_parser::GeneratorConfig,
+    map::{EnvType, Map},
+};
+
+/**
+ * Cells per lattice point of the coarsest noise octave
+ */
+const NOISE_SCALE: f32 = 8.0;
+const NOISE_OCTAVES: usize = 3;
+
+/**
+ * Smooth value noise in [0, 1): random values on a lattice, interpolated and summed over octaves
+ */
+fn value_noise(rows: usize, cols: usize, rng: &mut StdRng) -> Vec<f32> {
+    let mut noise = vec![0.0; rows * cols];
+    let mut total = 0.0;
+    for octave in 0..NOISE_OCTAVES {
+        let scale = NOISE_SCALE / (1 << octave) as f32;
+        let weight = 1.0 / (1 << octave) as f32;
+        let lattice_rows = (rows as f32 / scale) as usize + 2;
+        let lattice_cols = (cols as f32 / scale) as usize + 2;
+        let lattice = (0..lattice_rows * lattice_cols)
+            .map(|_| rng.gen::<f32>())
+            .collect::<Vec<_>>();
+        let at = |r: usize, c: usize| lattice[r * lattice_cols + c];
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        for (i, value) in noise.iter_mut().enumerate() {
+            let (y, x) = ((i / cols) as f32 / scale, (i % cols) as f32 / scale);
+            let (r, c) = (y as usize, x as usize);
+            let (ty, tx) = (smooth(y.fract()), smooth(x.fract()));
+            let top = at(r, c) + (at(r, c + 1) - at(r, c)) * tx;
+            let bottom = at(r + 1, c) + (at(r + 1, c + 1) - at(r + 1, c)) * tx;
+            *value += weight * (top + (bottom - top) * ty);
+        }
+        total += weight;
+    }
+    noise.iter_mut().for_each(|v| *v /= total);
+    noise
+}
+
+/**
+ * Turns the `n` cells of type `from` with the highest noise into `to`
+ */
+fn fill_by_noise(cells: &mut [EnvType], noise: &[f32], from: EnvType, to: EnvType, n: usize) {
+    let mut candidates = (0..cells.len())
+        .filter(|i| cells[*i] == from)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| noise[*b].total_cmp(&noise[*a]));
+    for i in candidates.into_iter().take(n) {
+        cells[i] = to;
+    }
+}
+
+/**
+ * Meandering river across the map along its longer side, of at most `budget` new water cells.
+ * Returns the number of new water cells.
+ */
+fn carve_river(
+    cells: &mut [EnvType],
+    rows: usize,
+    cols: usize,
+    budget: usize,
+    rng: &mut StdRng,
+) -> usize {
+    let horizontal = cols >= rows;
+    let (length, width) = if horizontal {
+        (cols, rows)
+    } else {
+        (rows, cols)
+    };
+    let mut position = rng.gen_range(0..width) as f32;
+    let mut drift = 0.0f32;
+    let mut carved = 0;
+    for step in 0..length {
+        if carved >= budget {
+            break;
+        }
+        drift = (drift + rng.gen_range(-0.5..0.5)).clamp(-1.0, 1.0);
+        position = (position + drift).clamp(0.0, (width - 1) as f32);
+        let across = position.round() as usize;
+        let i = if horizontal {
+            across * cols + step
+        } else {
+            step * cols + across
+        };
+        if cells[i] != EnvType::Water {
+            cells[i] = EnvType::Water;
+            carved += 1;
+        }
+    }
+    carved
+}
+
+/**
+ * Grows `patches` clusters of food from random land cells, one cell at a time in turn, until
+ * `target` cells are food
+ */
+fn grow_food(
+    cells: &mut [EnvType],
+    rows: usize,
+    cols: usize,
+    patches: usize,
+    target: usize,
+    rng: &mut StdRng,
+) {
+    let mut land = (0..cells.len())
+        .filter(|i| cells[*i] != EnvType::Water)
+        .collect::<Vec<_>>();
+    land.shuffle(rng);
+    let mut frontiers = land
+        .into_iter()
+        .take(patches)
+        .map(|i| vec![i])
+        .collect::<Vec<_>>();
+    let mut food = 0;
+    while food < target && frontiers.iter().any(|f| !f.is_empty()) {
+        for frontier in frontiers.iter_mut().filter(|f| !f.is_empty()) {
+            if food >= target {
+                break;
+            }
+            let i = frontier.swap_remove(rng.gen_range(0..frontier.len()));
+            if matches!(cells[i], EnvType::Water | EnvType::Food) {
+                continue;
+            }
+            cells[i] = EnvType::Food;
+            food += 1;
+            let (r, c) = (i / cols, i % cols);
+            if r > 0 {
+                frontier.push(i - cols);
+            }
+            if r + 1 < rows {
+                frontier.push(i + cols);
+            }
+            if c > 0 {
+                frontier.push(i - 1);
+            }
+            if c + 1 < cols {
+                frontier.push(i + 1);
+            }
+        }
+    }
+}
+
+/**
+ * A map with rivers and lakes, noise-shaped forests and clustered food patches covering the
+ * configured shares of the cells
+ */
+pub fn generate(cfg: &GeneratorConfig, rng: &mut StdRng) -> Map<u8> {
+    let (rows, cols) = (cfg.rows, cfg.cols);
+    let cells_count = rows * cols;
+    let share = |ratio: f32| (ratio * cells_count as f32).round() as usize;
+    let mut cells = vec![EnvType::Meadow; cells_count];
+
+    // Rivers take at most half of the water, lakes the rest
+    let water = share(cfg.water);
+    let mut rivers = 0;
+    for _ in 0..cfg.rivers {
+        rivers += carve_river(&mut cells, rows, cols, water / 2 - rivers, rng);
+    }
+    let lakes = value_noise(rows, cols, rng);
+    fill_by_noise(
+        &mut cells,
+        &lakes,
+        EnvType::Meadow,
+        EnvType::Water,
+        water - rivers,
+    );
+
+    let forest = value_noise(rows, cols, rng);
+    fill_by_noise(
+        &mut cells,
+        &forest,
+        EnvType::Meadow,
+        EnvType::Forest,
+        share(cfg.forest),
+    );
+
+    grow_food(
+        &mut cells,
+        rows,
+        cols,
+        cfg.food_patches,
+        share(cfg.food),
+        rng,
+    );
+    Map::new(
+        rows,
+        cols,
+        cells.iter().map(|c| c.get_index() as u8).collect(),
+    )
+}
+
+#[cfg(test)]
+mod generator_tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_generate() {
+        let cfg = GeneratorConfig {
+            rows: 25,
+            cols: 50,
+            seed: None,
+            forest: 0.2,
+            water: 0.1,
+            food: 0.05,
+            rivers: 1,
+            food_patches: 4,
+            regenerate: false,
+        };
+        let map = generate(&cfg, &mut StdRng::seed_from_u64(3));
+        let cells = &map[0..1250];
+        let count = |env: EnvType| {
+            cells
+                .iter()
+                .filter(|c| **c == env.get_index() as u8)
+                .count()
+        };
+        assert_eq!(count(EnvType::Water), 125);
+        // Forest can be overgrown by food
+        assert!((187..=250).contains(&count(EnvType::Forest)));
+        assert_eq!(count(EnvType::Food), 63);
+
+        let again = generate(&cfg, &mut StdRng::seed_from_u64(3));
+        assert_eq!(&again[0..1250], cells);
+    }
+}
@@ -1,12 +1,13 @@
 use std::ops::{Index, Range};
 
-use super::bmpg8::BmpImg;
+use super::bmpg8::{BmpError, BmpImg};
 use bevy::{
     prelude::Vec2,
     reflect::{FromReflect, Reflect},
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, PartialEq, Debug, Reflect, FromReflect)]
+#[derive(Clone, Copy, PartialEq, Debug, Reflect, FromReflect, Serialize, Deserialize)]
 pub enum EnvType {
     Meadow,
     Forest,
@@ -15,13 +16,13 @@ pub enum EnvType {
     Outside,
 }
 impl EnvType {
-    pub fn from_u8(val: u8) -> Self {
+    pub fn from_u8(val: u8) -> Option<Self> {
         match val {
-            0 => Self::Meadow,
-            1 => Self::Forest,
-            2 => Self::Food,
-            3 => Self::Water,
-            _ => panic!("Invalid environment type: {val}"),
+            0 => Some(Self::Meadow),
+            1 => Some(Self::Forest),
+            2 => Some(Self::Food),
+            3 => Some(Self::Water),
+            _ => None,
         }
     }
 
@@ -36,20 +37,220 @@ impl EnvType {
     }
 }
 
+/**
+ * Colours of the environment types in PNG maps. Every pixel gets the type of the nearest colour.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub meadow: [u8; 3],
+    pub forest: [u8; 3],
+    pub food: [u8; 3],
+    pub water: [u8; 3],
+}
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            meadow: [0, 156, 0],
+            forest: [0, 80, 0],
+            food: [255, 50, 0],
+            water: [0, 90, 255],
+        }
+    }
+}
+impl Palette {
+    pub fn colour(&self, env: EnvType) -> [u8; 3] {
+        match env {
+            EnvType::Meadow | EnvType::Outside => self.meadow,
+            EnvType::Forest => self.forest,
+            EnvType::Food => self.food,
+            EnvType::Water => self.water,
+        }
+    }
+    pub fn env_type(&self, rgb: [u8; 3]) -> EnvType {
+        let distance = |colour: &[u8; 3]| {
+            colour
+                .iter()
+                .zip(rgb)
+                .map(|(a, b)| (*a as i32 - b as i32).pow(2))
+                .sum::<i32>()
+        };
+        [
+            (&self.meadow, EnvType::Meadow),
+            (&self.forest, EnvType::Forest),
+            (&self.food, EnvType::Food),
+            (&self.water, EnvType::Water),
+        ]
+        .into_iter()
+        .min_by_key(|(colour, _)| distance(colour))
+        .map(|(_, env)| env)
+        .unwrap()
+    }
+}
+
 #[derive(Debug)]
 pub struct Map<T> {
     rows: usize,
     cols: usize,
     data: Vec<T>,
+    food: Vec<f32>,     // Share of its capacity left on each food cell, 0 elsewhere
+    food_changed: bool, // Since the last `take_food_changed`
 }
 impl Map<u8> {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let bmp = BmpImg::from_bytes(bytes);
-        Self::from(bmp)
+    /**
+     * Map of a BMP, whose 8-bit pixels are environment types and whose 24 and 32-bit pixels are
+     * colours of the palette
+     */
+    pub fn from_bytes(bytes: &[u8], palette: &Palette) -> Result<Self, BmpError> {
+        let bmp = BmpImg::from_bytes(bytes)?;
+        if bmp.bits != 8 {
+            let pixels = bmp
+                .data
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], u8::MAX])
+                .collect::<Vec<_>>();
+            return Ok(Self::from_rgba(bmp.rows, bmp.cols, &pixels, palette));
+        }
+        if let Some(i) = bmp.data.iter().position(|v| EnvType::from_u8(*v).is_none()) {
+            return Err(BmpError::InvalidValue {
+                value: bmp.data[i],
+                row: i / bmp.cols,
+                col: i % bmp.cols,
+            });
+        }
+        Ok(Self::new(bmp.rows, bmp.cols, bmp.data))
+    }
+    /**
+     * Map of an RGBA image with rows from top to bottom
+     */
+    pub fn from_rgba(rows: usize, cols: usize, pixels: &[u8], palette: &Palette) -> Self {
+        assert_eq!(pixels.len(), rows * cols * 4, "Image size does not match");
+        Self::new(
+            rows,
+            cols,
+            pixels
+                .chunks_exact(4)
+                .map(|p| palette.env_type([p[0], p[1], p[2]]).get_index() as u8)
+                .collect(),
+        )
+    }
+    pub fn new(rows: usize, cols: usize, data: Vec<u8>) -> Self {
+        assert_eq!(data.len(), rows * cols, "Map size does not match");
+        let mut map = Self {
+            rows,
+            cols,
+            data,
+            food: Vec::new(),
+            food_changed: false,
+        };
+        map.refill_food();
+        map
+    }
+    pub fn size(&self) -> [usize; 2] {
+        [self.rows, self.cols]
+    }
+    /**
+     * Palette colours of the cells, as RGBA rows from top to bottom. Food cells fade to meadow as
+     * their food is eaten.
+     */
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        self.data
+            .iter()
+            .zip(&self.food)
+            .flat_map(|(v, food)| {
+                let [r, g, b] = match EnvType::from_u8(*v) {
+                    Some(EnvType::Food) => {
+                        let (m, f) = (palette.meadow, palette.food);
+                        std::array::from_fn(|i| {
+                            (m[i] as f32 + (f[i] as f32 - m[i] as f32) * food).round() as u8
+                        })
+                    }
+                    Some(env) => palette.colour(env),
+                    None => [0; 3],
+                };
+                [r, g, b, u8::MAX]
+            })
+            .collect()
+    }
+    /**
+     * Fills every food cell to its capacity
+     */
+    pub fn refill_food(&mut self) {
+        self.food = self
+            .data
+            .iter()
+            .map(|v| {
+                if *v == EnvType::Food.get_index() as u8 {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        self.food_changed = true;
+    }
+    /**
+     * Share of its capacity left on each cell, row by row
+     */
+    pub fn food(&self) -> &[f32] {
+        &self.food
+    }
+    /**
+     * Restores food saved with `food`, e.g. by a checkpoint
+     */
+    pub fn set_food(&mut self, food: Vec<f32>) {
+        assert_eq!(
+            food.len(),
+            self.food.len(),
+            "Saved food does not match the map"
+        );
+        self.food = food;
+        self.food_changed = true;
+    }
+    /**
+     * Whether any food was eaten, grown back or refilled since the last call
+     */
+    pub fn take_food_changed(&mut self) -> bool {
+        std::mem::take(&mut self.food_changed)
+    }
+    /**
+     * Grows `share` of their capacity back on every food cell
+     */
+    pub fn regrow_food(&mut self, share: f32) {
+        for (v, food) in self.data.iter().zip(&mut self.food) {
+            if *v == EnvType::Food.get_index() as u8 && *food < 1.0 {
+                *food = (*food + share).min(1.0);
+                self.food_changed = true;
+            }
+        }
+    }
+    /**
+     * Share of its capacity left on the cell at a location, 0 if it is not a food cell
+     */
+    pub fn get_food(&self, loc: Vec2, x_lim: (f32, f32), y_lim: (f32, f32)) -> f32 {
+        self.cell(loc, x_lim, y_lim).map_or(0.0, |i| self.food[i])
+    }
+    /**
+     * Eats at most `share` of its capacity from the cell at a location. Returns the share eaten.
+     */
+    pub fn eat_food(&mut self, loc: Vec2, x_lim: (f32, f32), y_lim: (f32, f32), share: f32) -> f32 {
+        let Some(i) = self.cell(loc, x_lim, y_lim) else {
+            return 0.0;
+        };
+        let eaten = self.food[i].min(share);
+        self.food[i] -= eaten;
+        self.food_changed |= eaten > 0.0;
+        eaten
+    }
+    /**
+     * Index into `data` of the cell at a location inside the limits
+     */
+    fn cell(&self, loc: Vec2, x_lim: (f32, f32), y_lim: (f32, f32)) -> Option<usize> {
+        if !(x_lim.0..=x_lim.1).contains(&loc.x) || !(y_lim.0..=y_lim.1).contains(&loc.y) {
+            return None;
+        }
+        let (row, col) = self.get_index(loc, x_lim, y_lim);
+        Some(row.min(self.rows - 1) * self.cols + col.min(self.cols - 1))
     }
-    // pub fn size(&self) -> [usize; 2] {
-    //     [self.rows, self.cols]
-    // }
     /**
      * limits as (min, max)
      */
@@ -61,11 +262,10 @@ impl Map<u8> {
         (row, col)
     }
     pub fn get_env_type(&self, loc: Vec2, x_lim: (f32, f32), y_lim: (f32, f32)) -> EnvType {
-        if !(x_lim.0..=x_lim.1).contains(&loc.x) || !(y_lim.0..=y_lim.1).contains(&loc.y) {
-            return EnvType::Outside;
-        }
-        let (row, col) = self.get_index(loc, x_lim, y_lim);
-        EnvType::from_u8(self[(row.min(self.rows - 1), col.min(self.cols - 1))])
+        // Values are checked when the map is loaded
+        self.cell(loc, x_lim, y_lim)
+            .and_then(|i| EnvType::from_u8(self.data[i]))
+            .unwrap_or(EnvType::Outside)
     }
 }
 impl Index<Range<usize>> for Map<u8> {
@@ -81,12 +281,38 @@ impl Index<(usize, usize)> for Map<u8> {
         &self.data[row * self.cols + col]
     }
 }
-impl From<BmpImg> for Map<u8> {
-    fn from(value: BmpImg) -> Self {
-        Self {
-            rows: value.rows,
-            cols: value.cols,
-            data: value.data,
-        }
+
+#[cfg(test)]
+mod map_tests {
+    use super::*;
+
+    #[test]
+    fn test_food() {
+        // Meadow and food cells in a 2x1 world from (0, 0) to (2, 1)
+        let mut map = Map::new(1, 2, vec![0, 2]);
+        let lim = ((0.0, 2.0), (0.0, 1.0));
+        let (meadow, food) = (Vec2::new(0.2, 0.5), Vec2::new(1.8, 0.5));
+        assert!(map.take_food_changed());
+        assert_eq!(map.eat_food(meadow, lim.0, lim.1, 0.5), 0.0);
+        assert!(!map.take_food_changed());
+        assert_eq!(map.eat_food(food, lim.0, lim.1, 0.75), 0.75);
+        assert!(map.take_food_changed());
+        assert_eq!(map.eat_food(food, lim.0, lim.1, 0.75), 0.25);
+        assert_eq!(map.get_food(food, lim.0, lim.1), 0.0);
+
+        map.regrow_food(0.5);
+        assert_eq!(map.get_food(food, lim.0, lim.1), 0.5);
+        assert_eq!(map.get_food(meadow, lim.0, lim.1), 0.0);
+        let palette = Palette::default();
+        assert_eq!(map.to_rgba(&palette)[4..7], [128, 103, 0]);
+
+        map.regrow_food(0.75);
+        assert_eq!(map.get_food(food, lim.0, lim.1), 1.0);
+        map.take_food_changed();
+        map.regrow_food(0.5);
+        assert!(!map.take_food_changed());
+        map.eat_food(food, lim.0, lim.1, 1.0);
+        map.refill_food();
+        assert_eq!(map.get_food(food, lim.0, lim.1), 1.0);
     }
 }
@@ -1,3 +1,5 @@
 pub mod bmpg8;
+pub mod cli;
 pub mod config_parser;
+pub mod generator;
 pub mod map;
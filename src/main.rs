@@ -1,31 +1,84 @@
-use bevy::{prelude::*, window::PresentMode};
+use bevy::{log::LogPlugin, prelude::*, window::PresentMode};
 
 mod assets;
 mod camera_control;
 mod config;
 mod entities;
+mod evaluation;
 mod helpers;
 mod menus;
+mod metrics;
+mod replay;
 mod rl;
 mod states;
+mod sweep;
+mod tournament;
 
 use bevy_mod_picking::{DebugCursorPickingPlugin, DefaultPickingPlugins, PickingCameraBundle};
+use burn::tensor::backend::Backend;
 use camera_control::{CameraMovementPlugin, PrimaryCamera};
-use config::{ConfigRes, Map};
-use entities::EntityPlugin;
+use config::{ConfigRes, Map, MapGenerator, SimRng};
+use entities::{AgentType, EntityPlugin};
+use helpers::cli::Args;
 use menus::MenuPlugins;
-use rl::model_helpers::AgentModel;
+use rl::checkpoint;
+use rl::learner::Learner;
+use rl::model::ModelBackend;
+use rl::snapshot::{ObservationLayout, NUM_ACTIONS};
 use states::{AppState, GameState};
 
 #[derive(Component)]
 struct Actor {}
 
 fn main() {
-    let config = ConfigRes::default();
+    let args = Args::parse(std::env::args().skip(1));
+    if let Some(spec) = &args.sweep {
+        sweep::run_sweep(&args, spec);
+        return;
+    }
+    let mut config = ConfigRes::from_args(&args);
+    let replay = args.replay.as_deref().map(replay::Replay::load);
+    // Replays only need the recorded world, no models are loaded
+    if let Some(replay) = &replay {
+        replay.header.apply(&mut config.0);
+        config.0.rl.learn = false;
+        config.0.rl.headless = false;
+        config.0.rl.record = false;
+    }
+    let evaluate = args.evaluate || args.tournament.is_some();
+    if evaluate {
+        config.0.rl.learn = false;
+        config.0.rl.checkpoints.resume = false;
+    }
+    // Tournaments play many episodes per pairing, only their results are of interest
+    if args.tournament.is_some() {
+        config.0.rl.headless = true;
+    }
+    // Tournaments load their own models, evaluations would otherwise play untrained ones
+    let eval = &config.0.eval;
+    if args.tournament.is_none()
+        && args.evaluate
+        && config.0.rl.load_path.is_none()
+        && (eval.prey_path.is_none() || eval.predator_path.is_none())
+    {
+        eprintln!(
+            "Evaluation needs a model of each species: set load_path (--load), or prey_path \
+             (--prey) and predator_path (--predator)"
+        );
+        std::process::exit(1);
+    }
+    if let Some(seed) = config.0.world.seed {
+        ModelBackend::seed(seed);
+    }
     let learn = config.0.rl.learn;
-    let (prey_model, predator_model) = get_models(&config);
-    App::new()
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
+    let headless = config.0.rl.headless;
+    let mut app = App::new();
+    if headless {
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(LogPlugin::default())
+            .add_plugin(AssetPlugin::default());
+    } else {
+        app.add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 present_mode: if learn {
                     PresentMode::AutoNoVsync
@@ -38,46 +91,162 @@ fn main() {
         }))
         // .add_plugin(WorldInspectorPlugin::default())
         .add_plugins(DefaultPickingPlugins)
-        .add_plugin(DebugCursorPickingPlugin)
-        .insert_resource(rl::ReplayBufferPrey::new(config.0.rl.replay_buffer_size))
-        .insert_resource(rl::ReplayBufferPredator::new(
-            config.0.rl.replay_buffer_size,
-        ))
-        .insert_resource(prey_model)
-        .insert_resource(predator_model)
+        .add_plugin(DebugCursorPickingPlugin);
+    }
+    let replaying = replay.is_some();
+    let mut rng = SimRng::new(config.0.world.seed);
+    let mut map_seed = None; // Of a resumed run
+    if let Some(replay) = replay {
+        app.insert_resource(replay);
+    } else {
+        if !std::path::Path::new(&config.0.rl.save_path).is_dir() {
+            std::fs::create_dir_all(&config.0.rl.save_path)
+                .expect("Save path does not exist, could not create");
+        }
+        let (prey_model, predator_model) = if let Some(path) = &args.tournament {
+            let tournament = tournament::Tournament::new(path, &config.0);
+            let (prey, predator) = tournament.models(&config.0);
+            app.insert_resource(tournament)
+                .insert_resource(evaluation::Evaluation::new(&config.0));
+            (
+                rl::ModelPrey { model: prey },
+                rl::ModelPredator { model: predator },
+            )
+        } else {
+            let (mut prey_model, mut predator_model) = get_models(&config);
+            if evaluate {
+                if let Some(path) = &config.0.eval.prey_path {
+                    prey_model.model = checkpoint::load_learner(path, AgentType::Prey, &config.0);
+                }
+                if let Some(path) = &config.0.eval.predator_path {
+                    predator_model.model =
+                        checkpoint::load_learner(path, AgentType::Predator, &config.0);
+                }
+                prey_model.model.disable_exploration();
+                predator_model.model.disable_exploration();
+                app.insert_resource(evaluation::Evaluation::new(&config.0));
+            } else {
+                app.insert_resource(checkpoint::CheckpointManager::new(&config.0))
+                    .insert_resource(metrics::MetricsLog::new(&config.0.rl));
+            }
+            (prey_model, predator_model)
+        };
+        // Loaded models keep the algorithm they were trained with
+        let (prey_algorithm, predator_algorithm) = (
+            prey_model.model.algorithm(),
+            predator_model.model.algorithm(),
+        );
+        if config.0.rl.checkpoints.resume {
+            let (prey_buffer, predator_buffer, state, simulation) =
+                checkpoint::load_training(&config.0, prey_algorithm, predator_algorithm);
+            println!("Resuming from update {}", state.update);
+            if let Some(simulation) = simulation {
+                rng = SimRng(simulation.rng.clone());
+                app.insert_resource(simulation);
+            }
+            map_seed = state.map_seed;
+            app.insert_resource(prey_buffer)
+                .insert_resource(predator_buffer)
+                .insert_resource(state);
+        } else {
+            app.insert_resource(rl::ReplayBufferPrey::new(&config.0.rl, prey_algorithm))
+                .insert_resource(rl::ReplayBufferPredator::new(
+                    &config.0.rl,
+                    predator_algorithm,
+                ));
+        }
+        app.insert_resource(prey_model)
+            .insert_resource(predator_model);
+    }
+    let generator = config.0.world.generator.as_ref().map(|g| match map_seed {
+        Some(seed) => MapGenerator { seed },
+        None => MapGenerator::new(g, &mut rng),
+    });
+    if !replaying {
+        app.insert_resource(replay::Recorder::new(&config.0, generator.map(|g| g.seed)));
+    }
+    if let Some(generator) = generator {
+        app.insert_resource(generator);
+    }
+    let map_loader = assets::MapLoader {
+        palette: config.0.world.palette.clone().unwrap_or_default(),
+        texture: !headless,
+    };
+    app.insert_resource(rng)
         .insert_resource(config)
         .add_asset::<assets::MapAsset>()
-        .add_asset_loader(assets::MapLoader)
+        .add_asset_loader(map_loader)
         .init_resource::<Map>()
         .add_state::<AppState>()
         .add_state::<GameState>()
-        .add_plugins(MenuPlugins)
-        .add_plugin(CameraMovementPlugin)
-        .add_plugin(EntityPlugin)
         .add_startup_system(setup)
-        .run();
+        .add_system(exit_on_map_error);
+    if !headless {
+        app.add_plugins(MenuPlugins)
+            .add_plugin(CameraMovementPlugin)
+            .add_startup_system(setup_scene.after(setup));
+    }
+    app.add_plugin(EntityPlugin {
+        headless,
+        replay: replaying,
+        evaluate,
+    })
+    .run();
 }
 
 fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     mut map: ResMut<Map>,
     mut game_state: ResMut<NextState<GameState>>,
     mut app_state: ResMut<NextState<AppState>>,
     config: Res<ConfigRes>,
     assets: Res<AssetServer>,
+    generator: Option<Res<MapGenerator>>,
+    mut maps: ResMut<Assets<assets::MapAsset>>,
+    images: Option<ResMut<Assets<Image>>>,
 ) {
-    map.map = assets.load(config.0.world.map.clone() + ".map");
+    let world = &config.0.world;
+    if let (Some(generator), Some(cfg)) = (generator, &world.generator) {
+        let generated = generator.generate(cfg);
+        if let Some(mut images) = images {
+            let palette = world.palette.clone().unwrap_or_default();
+            map.texture = images.add(assets::map_texture(&generated, &palette));
+        }
+        map.map = maps.add(assets::MapAsset(generated));
+        map.drawn = true;
+    } else {
+        let (terrain, texture) = assets::map_paths(&world.map);
+        map.map = assets.load(terrain);
+        if !config.0.rl.headless {
+            map.texture = assets.load(texture);
+        }
+        map.drawn = world.map.ends_with(".map.png");
+    }
 
-    if config.0.rl.learn {
+    if config.0.rl.learn || config.0.rl.headless {
         game_state.set(GameState::Skip);
         app_state.set(AppState::InGame)
     }
+}
+
+/**
+ * The asset server logs why the map could not be loaded, without it the app would wait forever
+ */
+fn exit_on_map_error(map: Res<Map>, assets: Res<AssetServer>, config: Res<ConfigRes>) {
+    if assets.get_load_state(&map.map) == bevy::asset::LoadState::Failed {
+        eprintln!("Could not load map {}", config.0.world.map);
+        std::process::exit(1);
+    }
+}
 
+fn setup_scene(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    config: Res<ConfigRes>,
+    map: Res<Map>,
+) {
     // plane
     let world_size = Vec2::new(config.0.world.world_width, config.0.world.world_height);
-    let map_path = config.0.world.map.clone();
     commands.spawn(PbrBundle {
         mesh: meshes.add(
             shape::Quad {
@@ -86,7 +255,7 @@ fn setup(
             }
             .into(),
         ),
-        material: materials.add(assets.load(map_path + ".bmp").into()),
+        material: materials.add(map.texture.clone().into()),
         transform: Transform::from_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2)),
         ..default()
     });
@@ -129,35 +298,44 @@ fn setup(
 }
 
 fn get_models(cfg: &ConfigRes) -> (rl::ModelPrey, rl::ModelPredator) {
-    if !std::path::Path::new(&cfg.0.rl.save_path).is_dir() {
-        std::fs::create_dir_all(&cfg.0.rl.save_path)
-            .expect("Save path does not exist, could not create");
-    }
-    if let Some(path) = &cfg.0.rl.load_path {
+    if let Some((path, step)) = checkpoint::selected_checkpoint(&cfg.0) {
+        println!("Checkpoint: update {step}");
+        (
+            rl::ModelPrey {
+                model: Learner::load(&path, "prey", &cfg.0),
+            },
+            rl::ModelPredator {
+                model: Learner::load(&path, "predator", &cfg.0),
+            },
+        )
+    } else if let Some(path) = &cfg.0.rl.load_path {
         assert!(std::path::Path::new(&path).is_dir());
         if let Some(name) = &cfg.0.rl.load_model_name {
             (
                 rl::ModelPrey {
-                    model: AgentModel::load(path, format!("prey_{name}").as_str(), cfg.0.rl.learning_rate),
+                    model: Learner::load(path, format!("prey_{name}").as_str(), &cfg.0),
                 },
                 rl::ModelPredator {
-                    model: AgentModel::load(path, format!("predator_{name}").as_str(), cfg.0.rl.learning_rate),
+                    model: Learner::load(path, format!("predator_{name}").as_str(), &cfg.0),
                 },
             )
         } else {
             let mut files = std::fs::read_dir(path)
                 .unwrap()
                 .filter_map(|f| {
-                    f.ok().and_then(|f| {
-                        f.file_name().into_string().ok().and_then(|f| {
-                            std::path::Path::new(&f).file_stem().and_then(|f| {
-                                f.to_str().and_then(|f| {
-                                    f.split_once('_')
-                                        .map(|(t, n)| (t.to_string(), n.to_string()))
+                    // Descriptions and config snapshots share the name of their model
+                    f.ok()
+                        .filter(|f| f.path().extension().is_some_and(|e| e == "model"))
+                        .and_then(|f| {
+                            f.file_name().into_string().ok().and_then(|f| {
+                                std::path::Path::new(&f).file_stem().and_then(|f| {
+                                    f.to_str().and_then(|f| {
+                                        f.split_once('_')
+                                            .map(|(t, n)| (t.to_string(), n.to_string()))
+                                    })
                                 })
                             })
                         })
-                    })
                 })
                 .collect::<Vec<_>>();
             files.sort_by(|a, b| b.1.cmp(&a.1));
@@ -175,30 +353,34 @@ fn get_models(cfg: &ConfigRes) -> (rl::ModelPrey, rl::ModelPredator) {
             println!("Predator model: {predator_name}");
             (
                 rl::ModelPrey {
-                    model: AgentModel::load(path, format!("prey_{prey_name}").as_str(), cfg.0.rl.learning_rate),
+                    model: Learner::load(path, format!("prey_{prey_name}").as_str(), &cfg.0),
                 },
                 rl::ModelPredator {
-                    model: AgentModel::load(path, format!("predator_{predator_name}").as_str(), cfg.0.rl.learning_rate),
+                    model: Learner::load(
+                        path,
+                        format!("predator_{predator_name}").as_str(),
+                        &cfg.0,
+                    ),
                 },
             )
         }
     } else {
         (
             rl::ModelPrey {
-                model: AgentModel::new(
-                    11 + cfg.0.prey.vision_rays * 15 + cfg.0.prey.hearing_rays * 10,
-                    11,
-                    &cfg.0.rl.layers,
-                    cfg.0.rl.learning_rate,
+                model: Learner::new(
+                    ObservationLayout::new(&cfg.0.prey).inputs,
+                    NUM_ACTIONS,
+                    &cfg.0.rl,
+                    cfg.0.rl.prey_algorithm,
                     entities::AgentType::Prey,
                 ),
             },
             rl::ModelPredator {
-                model: AgentModel::new(
-                    11 + cfg.0.predator.vision_rays * 15 + cfg.0.predator.hearing_rays * 10,
-                    11,
-                    &cfg.0.rl.layers,
-                    cfg.0.rl.learning_rate,
+                model: Learner::new(
+                    ObservationLayout::new(&cfg.0.predator).inputs,
+                    NUM_ACTIONS,
+                    &cfg.0.rl,
+                    cfg.0.rl.predator_algorithm,
                     entities::AgentType::Predator,
                 ),
             },
@@ -0,0 +1,237 @@
+use bevy::prelude::*;
+
+use crate::{
+    metrics::{History, HistoryEntry, HISTORY_LENGTH},
+    states::AppState,
+};
+
+const CHART_WIDTH: f32 = 200.0;
+const CHART_HEIGHT: f32 = 50.0;
+const LINE_WIDTH: f32 = 2.0;
+const TOGGLE_KEY: KeyCode = KeyCode::C;
+
+const PREY_COLOR: Color = Color::rgb(0.4, 1.0, 0.4);
+const PREDATOR_COLOR: Color = Color::rgb(1.0, 0.4, 0.4);
+
+pub struct ChartsPlugin;
+
+impl Plugin for ChartsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChartsVisible>()
+            .add_system(spawn_charts.in_schedule(OnEnter(AppState::InGame)))
+            .add_system(despawn_charts.in_schedule(OnExit(AppState::InGame)))
+            .add_systems((toggle_charts, update_charts).in_set(OnUpdate(AppState::InGame)));
+    }
+}
+
+#[derive(Resource, Default)]
+struct ChartsVisible(bool);
+
+#[derive(Component)]
+struct ChartPanel {}
+
+#[derive(Component)]
+struct Chart {
+    kind: ChartKind,
+}
+
+#[derive(Component)]
+struct ChartTitle {
+    kind: ChartKind,
+}
+
+#[derive(Clone, Copy)]
+enum ChartKind {
+    Population,
+    Loss,
+    Energy,
+    Epsilon,
+}
+impl ChartKind {
+    const ALL: [ChartKind; 4] = [
+        ChartKind::Population,
+        ChartKind::Loss,
+        ChartKind::Energy,
+        ChartKind::Epsilon,
+    ];
+    fn title(&self) -> &'static str {
+        match self {
+            ChartKind::Population => "Population",
+            ChartKind::Loss => "Loss",
+            ChartKind::Energy => "Energy",
+            ChartKind::Epsilon => "Epsilon",
+        }
+    }
+    /**
+     * Prey and predator values of an update
+     */
+    fn values(&self, entry: &HistoryEntry) -> [Option<f32>; 2] {
+        match self {
+            ChartKind::Population => [Some(entry.population.0), Some(entry.population.1)],
+            ChartKind::Loss => [Some(entry.loss.0), Some(entry.loss.1)],
+            ChartKind::Energy => [Some(entry.energy.0), Some(entry.energy.1)],
+            ChartKind::Epsilon => [entry.eps.0, entry.eps.1],
+        }
+    }
+}
+
+fn text_style(asset_server: &AssetServer, color: Color) -> TextStyle {
+    TextStyle {
+        font: asset_server.load("fonts/Start.otf"),
+        font_size: 10.0,
+        color,
+    }
+}
+
+fn spawn_charts(
+    mut commands: Commands,
+    visible: Res<ChartsVisible>,
+    asset_server: Res<AssetServer>,
+) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        right: Val::Px(10.0),
+                        top: Val::Px(100.0),
+                        ..default()
+                    },
+                    flex_direction: FlexDirection::Column,
+                    padding: UiRect::all(Val::Px(5.0)),
+                    ..default()
+                },
+                background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                visibility: if visible.0 {
+                    Visibility::Visible
+                } else {
+                    Visibility::Hidden
+                },
+                ..default()
+            },
+            ChartPanel {},
+        ))
+        .with_children(|parent| {
+            for kind in ChartKind::ALL {
+                parent.spawn((
+                    TextBundle::from_sections([
+                        TextSection::new(kind.title(), text_style(&asset_server, Color::WHITE)),
+                        TextSection::new("", text_style(&asset_server, PREY_COLOR)),
+                        TextSection::new("", text_style(&asset_server, PREDATOR_COLOR)),
+                    ])
+                    .with_style(Style {
+                        margin: UiRect::top(Val::Px(5.0)),
+                        ..default()
+                    }),
+                    ChartTitle { kind },
+                ));
+                parent.spawn((
+                    NodeBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(CHART_WIDTH), Val::Px(CHART_HEIGHT)),
+                            ..default()
+                        },
+                        background_color: Color::rgba(1.0, 1.0, 1.0, 0.1).into(),
+                        ..default()
+                    },
+                    Chart { kind },
+                ));
+            }
+        });
+}
+
+fn despawn_charts(mut commands: Commands, query: Query<Entity, With<ChartPanel>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn toggle_charts(
+    mut visible: ResMut<ChartsVisible>,
+    mut query: Query<&mut Visibility, With<ChartPanel>>,
+    keyboard_input: Res<Input<KeyCode>>,
+) {
+    if keyboard_input.just_pressed(TOGGLE_KEY) {
+        visible.0 = !visible.0;
+        for mut visibility in &mut query {
+            *visibility = if visible.0 {
+                Visibility::Visible
+            } else {
+                Visibility::Hidden
+            };
+        }
+    }
+}
+
+/**
+ * Redraws the charts as line segments (one node per pair of consecutive values) whenever the
+ * history changes
+ */
+fn update_charts(
+    mut commands: Commands,
+    history: Res<History>,
+    charts: Query<(Entity, &Chart)>,
+    new_charts: Query<(), Added<Chart>>,
+    mut titles: Query<(&mut Text, &ChartTitle)>,
+) {
+    if !history.is_changed() && new_charts.is_empty() {
+        return;
+    }
+    let Some(last) = history.entries.back() else {
+        return;
+    };
+    for (mut text, title) in &mut titles {
+        let [prey, predator] = title.kind.values(last);
+        let format = |v: Option<f32>| v.map_or("-".to_string(), |v| format!("{v:.2}"));
+        text.sections[1].value = format!(" {}", format(prey));
+        text.sections[2].value = format!(" {}", format(predator));
+    }
+
+    let step = CHART_WIDTH / (HISTORY_LENGTH - 1) as f32;
+    for (entity, chart) in &charts {
+        let values = history
+            .entries
+            .iter()
+            .map(|e| chart.kind.values(e))
+            .collect::<Vec<_>>();
+        let (min, max) = values
+            .iter()
+            .flatten()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+        let range = if max > min { max - min } else { 1.0 };
+        let scale = |v: f32| (v - min) / range * (CHART_HEIGHT - LINE_WIDTH);
+
+        commands.entity(entity).despawn_descendants();
+        commands.entity(entity).with_children(|parent| {
+            for (series, color) in [PREY_COLOR, PREDATOR_COLOR].into_iter().enumerate() {
+                for (i, pair) in values.windows(2).enumerate() {
+                    let (Some(v0), Some(v1)) = (pair[0][series], pair[1][series]) else {
+                        continue;
+                    };
+                    let (y0, y1) = (scale(v0), scale(v1));
+                    parent.spawn(NodeBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            position: UiRect {
+                                left: Val::Px(i as f32 * step),
+                                bottom: Val::Px(y0.min(y1)),
+                                ..default()
+                            },
+                            size: Size::new(
+                                Val::Px(step.max(LINE_WIDTH)),
+                                Val::Px((y1 - y0).abs().max(LINE_WIDTH)),
+                            ),
+                            ..default()
+                        },
+                        background_color: color.into(),
+                        ..default()
+                    });
+                }
+            }
+        });
+    }
+}
@@ -1,8 +1,10 @@
 use bevy::{app::PluginGroupBuilder, prelude::*};
 
+mod charts;
 mod game_menu;
 mod main_menu;
 mod pause_menu;
+mod replay_menu;
 
 use main_menu::MainMenuPlugin;
 use pause_menu::PauseMenuPlugin;
@@ -24,6 +26,8 @@ impl PluginGroup for MenuPlugins {
             .add(PauseMenuPlugin)
             .add(ToggleAppStatePlugin)
             .add(game_menu::GameMenuPlugin)
+            .add(charts::ChartsPlugin)
+            .add(replay_menu::ReplayMenuPlugin)
     }
 }
 
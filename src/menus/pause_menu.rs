@@ -1,10 +1,6 @@
 use bevy::{app::AppExit, prelude::*};
 
-use crate::{
-    config::ConfigRes,
-    rl::{ModelPredator, ModelPrey},
-    states::AppState,
-};
+use crate::{entities::SaveEvent, states::AppState};
 
 pub struct PauseMenuPlugin;
 
@@ -24,15 +20,12 @@ struct ExitButton {}
 
 fn button_click_handler(
     mut exit: EventWriter<AppExit>,
+    mut save: EventWriter<SaveEvent>,
     query: Query<&Interaction, With<ExitButton>>,
-    model_prey: Res<ModelPrey>,
-    model_predator: Res<ModelPredator>,
-    config: Res<ConfigRes>,
 ) {
     for interaction in &query {
         if *interaction == Interaction::Clicked {
-            model_prey.model.save(&config.0.rl.save_path);
-            model_predator.model.save(&config.0.rl.save_path);
+            save.send(SaveEvent);
             exit.send(AppExit);
         }
     }
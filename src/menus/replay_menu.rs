@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+
+use crate::{config::FPS, replay::Replay, states::AppState};
+
+const PAUSE_KEY: KeyCode = KeyCode::P;
+const RESTART_KEY: KeyCode = KeyCode::Home;
+const MIN_SPEED: f32 = 0.125;
+const MAX_SPEED: f32 = 16.0;
+
+pub struct ReplayMenuPlugin;
+
+impl Plugin for ReplayMenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            spawn_replay_menu
+                .in_schedule(OnEnter(AppState::InGame))
+                .run_if(resource_exists::<Replay>()),
+        )
+        .add_system(despawn_replay_menu.in_schedule(OnExit(AppState::InGame)))
+        .add_systems(
+            (replay_controls, update_replay_menu)
+                .in_set(OnUpdate(AppState::InGame))
+                .distributive_run_if(resource_exists::<Replay>()),
+        );
+    }
+}
+
+#[derive(Component)]
+struct ReplayMenu {}
+
+fn spawn_replay_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/Start.otf"),
+                font_size: 10.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Px(10.0),
+                top: Val::Px(100.0),
+                ..default()
+            },
+            ..default()
+        }),
+        ReplayMenu {},
+    ));
+}
+
+fn despawn_replay_menu(mut commands: Commands, query: Query<Entity, With<ReplayMenu>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/**
+ * P pauses, left and right seek by a second (ten with shift), up and down change the speed and
+ * Home restarts the replay
+ */
+fn replay_controls(mut replay: ResMut<Replay>, keyboard_input: Res<Input<KeyCode>>) {
+    let seconds = if keyboard_input.pressed(KeyCode::LShift) {
+        10.0
+    } else {
+        1.0
+    };
+    if keyboard_input.just_pressed(PAUSE_KEY) {
+        replay.paused = !replay.paused;
+    }
+    if keyboard_input.just_pressed(KeyCode::Left) {
+        replay.seek(-seconds * FPS);
+    }
+    if keyboard_input.just_pressed(KeyCode::Right) {
+        replay.seek(seconds * FPS);
+    }
+    if keyboard_input.just_pressed(KeyCode::Up) {
+        replay.speed = (replay.speed * 2.0).min(MAX_SPEED);
+    }
+    if keyboard_input.just_pressed(KeyCode::Down) {
+        replay.speed = (replay.speed / 2.0).max(MIN_SPEED);
+    }
+    if keyboard_input.just_pressed(RESTART_KEY) {
+        replay.position = 0.0;
+    }
+}
+
+fn update_replay_menu(replay: Res<Replay>, mut query: Query<&mut Text, With<ReplayMenu>>) {
+    if !replay.is_changed() {
+        return;
+    }
+    for mut text in &mut query {
+        text.sections[0].value = format!(
+            "Replay {}/{}\nSpeed x{}{}",
+            replay.frame() + 1,
+            replay.frames.len(),
+            replay.speed,
+            if replay.paused { "\nPaused" } else { "" }
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use bevy::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::{
+    entities::AgentType,
+    helpers::config_parser::{MetricsFormat, RLConfig},
+    rl::{model_helpers::timestamp, UpdateStats},
+};
+
+/**
+ * Events of one species since the last model update
+ */
+#[derive(Default, Debug)]
+pub struct SpeciesMetrics {
+    pub reward_sum: f32,
+    pub rewards: usize,
+    pub births: usize,
+    pub starvations: usize,
+    pub old_age: usize,
+    pub killed: usize,
+    pub eaten: usize, // Frames in which an agent ate
+    pub episode_length_sum: usize,
+    pub episodes: usize,
+}
+impl SpeciesMetrics {
+    pub fn add_reward(&mut self, reward: f32) {
+        self.reward_sum += reward;
+        self.rewards += 1;
+    }
+    pub fn end_episode(&mut self, length: usize) {
+        self.episode_length_sum += length;
+        self.episodes += 1;
+    }
+    /**
+     * Columns of this species, prefixed with its name
+     */
+    fn columns(&self, prefix: &str, summary: &SpeciesSummary) -> Vec<(String, Value)> {
+        let mean = |sum: f32, count: usize| (count > 0).then(|| sum / count as f32);
+        vec![
+            ("population", summary.population.into()),
+            ("births", self.births.into()),
+            ("starvations", self.starvations.into()),
+            ("old_age", self.old_age.into()),
+            ("killed", self.killed.into()),
+            ("eaten", self.eaten.into()),
+            (
+                "mean_episode_length",
+                mean(self.episode_length_sum as f32, self.episodes).into(),
+            ),
+            ("mean_reward", mean(self.reward_sum, self.rewards).into()),
+            ("loss", summary.stats.map(|s| s.loss).into()),
+            ("mean_q", summary.stats.map(|s| s.mean_value).into()),
+            ("eps", summary.eps.into()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (format!("{prefix}_{name}"), value))
+        .collect()
+    }
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct Metrics {
+    pub prey: SpeciesMetrics,
+    pub predator: SpeciesMetrics,
+}
+impl Metrics {
+    pub fn get_mut(&mut self, t: AgentType) -> &mut SpeciesMetrics {
+        match t {
+            AgentType::Prey => &mut self.prey,
+            AgentType::Predator => &mut self.predator,
+        }
+    }
+}
+
+pub const HISTORY_LENGTH: usize = 200;
+
+/**
+ * Values of both species at one update, as (prey, predator)
+ */
+#[derive(Default, Debug, Clone, Copy)]
+pub struct HistoryEntry {
+    pub population: (f32, f32),
+    pub loss: (f32, f32),
+    pub energy: (f32, f32),
+    pub eps: (Option<f32>, Option<f32>),
+}
+
+/**
+ * The last `HISTORY_LENGTH` updates, shown in the HUD charts
+ */
+#[derive(Resource, Default, Debug)]
+pub struct History {
+    pub entries: VecDeque<HistoryEntry>,
+}
+impl History {
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.entries.len() == HISTORY_LENGTH {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/**
+ * State of a species at the time of an update. `stats` is missing if it was not trained.
+ */
+pub struct SpeciesSummary {
+    pub population: usize,
+    pub stats: Option<UpdateStats>,
+    pub eps: Option<f32>,
+}
+
+/**
+ * Writes one row per model update to `metrics_<timestamp>.csv` or `.jsonl` in the save path
+ */
+#[derive(Resource)]
+pub struct MetricsLog {
+    format: MetricsFormat,
+    writer: Option<BufWriter<File>>,
+    header_written: bool,
+}
+impl MetricsLog {
+    pub fn new(cfg: &RLConfig) -> Self {
+        let format = cfg.metrics_format.unwrap_or_default();
+        let writer = cfg.metrics_format.map(|format| {
+            let extension = match format {
+                MetricsFormat::Csv => "csv",
+                MetricsFormat::Jsonl => "jsonl",
+            };
+            let path = format!("{}/metrics_{}.{extension}", cfg.save_path, timestamp());
+            BufWriter::new(File::create(path).expect("Could not create metrics file"))
+        });
+        Self {
+            format,
+            writer,
+            header_written: false,
+        }
+    }
+    pub fn write(
+        &mut self,
+        update: usize,
+        metrics: &Metrics,
+        prey: SpeciesSummary,
+        predator: SpeciesSummary,
+    ) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let mut row = vec![("update".to_string(), update.into())];
+        row.extend(metrics.prey.columns("prey", &prey));
+        row.extend(metrics.predator.columns("predator", &predator));
+
+        let line = match self.format {
+            MetricsFormat::Csv => {
+                if !self.header_written {
+                    let header = row.iter().map(|(name, _)| name.as_str());
+                    writeln!(writer, "{}", header.collect::<Vec<_>>().join(","))
+                        .expect("Could not write metrics");
+                    self.header_written = true;
+                }
+                row.into_iter()
+                    .map(|(_, value)| match value {
+                        Value::Null => String::new(),
+                        value => value.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }
+            MetricsFormat::Jsonl => {
+                Value::Object(row.into_iter().collect::<Map<_, _>>()).to_string()
+            }
+        };
+        writeln!(writer, "{line}").expect("Could not write metrics");
+        // Flushed every update, so interrupted runs keep their metrics
+        writer.flush().expect("Could not write metrics");
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entities::{Action, Agent, AgentType},
+    helpers::config_parser::{Config, GeneratorConfig},
+    rl::model_helpers::timestamp,
+};
+
+/**
+ * First line of a replay file, the world the episode was recorded in. The generator's seed is that
+ * of the first generated map.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplayHeader {
+    pub map: String,
+    pub world_width: f32,
+    pub world_height: f32,
+    pub generator: Option<GeneratorConfig>,
+}
+impl ReplayHeader {
+    /**
+     * Makes the config describe the recorded world
+     */
+    pub fn apply(&self, config: &mut Config) {
+        config.world.map = self.map.clone();
+        config.world.world_width = self.world_width;
+        config.world.world_height = self.world_height;
+        config.world.generator = self.generator.clone();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentRecord {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub agent_type: AgentType,
+    pub location: (f32, f32),
+    pub direction: f32,
+    pub speed: f32,
+    pub energy: f32,
+    pub alive: bool,
+    pub action: usize,
+}
+impl AgentRecord {
+    fn new(e: Entity, agent: &Agent) -> Self {
+        Self {
+            id: e.to_bits(),
+            agent_type: agent.agent_type,
+            location: agent.location.into(),
+            direction: agent.direction,
+            speed: agent.speed,
+            energy: agent.energy,
+            alive: agent.alive,
+            action: agent.action.to_action_index(),
+        }
+    }
+    /**
+     * Agent as it was recorded. Its life and sensory state are not recorded.
+     */
+    pub fn to_agent(&self) -> Agent {
+        let mut agent = Agent::new(self.agent_type, self.location.into(), self.direction, 0);
+        agent.speed = self.speed;
+        agent.energy = self.energy;
+        agent.alive = self.alive;
+        agent.action = Action::from_action_index(self.action);
+        agent
+    }
+}
+
+/**
+ * One line of a replay file. Births and deaths are ids of agents which appeared or stopped being
+ * alive since the previous frame. `map_seed` is only set in frames with a newly generated map.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FrameRecord {
+    pub frame: usize,
+    pub agents: Vec<AgentRecord>,
+    pub births: Vec<u64>,
+    pub deaths: Vec<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map_seed: Option<u64>,
+}
+
+/**
+ * Writes every simulated frame to `replay_<timestamp>.jsonl` in the save path if `record` is set
+ */
+#[derive(Resource)]
+pub struct Recorder {
+    writer: Option<BufWriter<File>>,
+    frame: usize,
+    previous: HashMap<u64, bool>, // Whether each agent of the previous frame was alive
+    map_seed: Option<u64>,        // Seed of the last recorded map
+}
+impl Recorder {
+    pub fn new(config: &Config, map_seed: Option<u64>) -> Self {
+        let writer = config.rl.record.then(|| {
+            let path = format!("{}/replay_{}.jsonl", config.rl.save_path, timestamp());
+            let mut writer =
+                BufWriter::new(File::create(path).expect("Could not create replay file"));
+            let header = ReplayHeader {
+                map: config.world.map.clone(),
+                world_width: config.world.world_width,
+                world_height: config.world.world_height,
+                generator: config.world.generator.clone().map(|g| GeneratorConfig {
+                    seed: map_seed,
+                    ..g
+                }),
+            };
+            serde_json::to_writer(&mut writer, &header).expect("Could not write replay");
+            writeln!(writer).expect("Could not write replay");
+            writer
+        });
+        Self {
+            writer,
+            frame: 0,
+            previous: HashMap::new(),
+            map_seed,
+        }
+    }
+    pub fn write<'a>(
+        &mut self,
+        agents: impl Iterator<Item = (Entity, &'a Agent)>,
+        map_seed: Option<u64>,
+    ) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let agents = agents
+            .map(|(e, a)| AgentRecord::new(e, a))
+            .collect::<Vec<_>>();
+        let current = agents
+            .iter()
+            .map(|a| (a.id, a.alive))
+            .collect::<HashMap<_, _>>();
+        let births = agents
+            .iter()
+            .filter(|a| !self.previous.contains_key(&a.id))
+            .map(|a| a.id)
+            .collect();
+        let mut deaths = self
+            .previous
+            .iter()
+            .filter(|(id, alive)| **alive && !current.get(id).copied().unwrap_or(false))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        deaths.sort_unstable();
+        let record = FrameRecord {
+            frame: self.frame,
+            agents,
+            births,
+            deaths,
+            map_seed: map_seed.filter(|_| map_seed != self.map_seed),
+        };
+        serde_json::to_writer(&mut *writer, &record).expect("Could not write replay");
+        writeln!(writer).expect("Could not write replay");
+        self.frame += 1;
+        self.previous = current;
+        self.map_seed = map_seed;
+    }
+}
+
+/**
+ * A loaded replay and the state of its playback. Present only in replay mode.
+ */
+#[derive(Resource)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub frames: Vec<FrameRecord>,
+    pub position: f32,
+    pub speed: f32,
+    pub paused: bool,
+    pub shown: Option<usize>, // Frame currently applied to the agents
+}
+impl Replay {
+    pub fn load(path: &str) -> Self {
+        let file = File::open(path).expect("Could not open replay file");
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().expect("Empty replay file");
+        let header = serde_json::from_str(&header.expect("Could not read replay file"))
+            .expect("Could not parse replay header");
+        let frames = lines
+            .map(|line| {
+                serde_json::from_str(&line.expect("Could not read replay file"))
+                    .expect("Could not parse replay frame")
+            })
+            .collect::<Vec<_>>();
+        assert!(!frames.is_empty(), "Replay file contains no frames");
+        Self {
+            header,
+            frames,
+            position: 0.0,
+            speed: 1.0,
+            paused: false,
+            shown: None,
+        }
+    }
+    pub fn frame(&self) -> usize {
+        self.position as usize
+    }
+    /**
+     * Seed of the generated map shown in a frame
+     */
+    pub fn map_seed(&self, frame: usize) -> Option<u64> {
+        self.frames[..=frame]
+            .iter()
+            .rev()
+            .find_map(|f| f.map_seed)
+            .or_else(|| self.header.generator.as_ref().and_then(|g| g.seed))
+    }
+    /**
+     * Moves by `speed` frames, pausing at the last one
+     */
+    pub fn advance(&mut self) {
+        if !self.paused {
+            self.seek(self.speed);
+        }
+        if self.frame() == self.frames.len() - 1 {
+            self.paused = true;
+        }
+    }
+    pub fn seek(&mut self, frames: f32) {
+        self.position = (self.position + frames).clamp(0.0, (self.frames.len() - 1) as f32);
+    }
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+
+    #[test]
+    fn test_map_seed() {
+        let frame = |frame, map_seed| FrameRecord {
+            frame,
+            agents: Vec::new(),
+            births: Vec::new(),
+            deaths: Vec::new(),
+            map_seed,
+        };
+        let generator = GeneratorConfig {
+            rows: 2,
+            cols: 2,
+            seed: Some(1),
+            forest: 0.0,
+            water: 0.0,
+            food: 0.0,
+            rivers: 0,
+            food_patches: 0,
+            regenerate: true,
+        };
+        let replay = Replay {
+            header: ReplayHeader {
+                map: String::new(),
+                world_width: 1.0,
+                world_height: 1.0,
+                generator: Some(generator),
+            },
+            frames: vec![frame(0, None), frame(1, Some(2)), frame(2, None)],
+            position: 0.0,
+            speed: 1.0,
+            paused: false,
+            shown: None,
+        };
+        assert_eq!(replay.map_seed(0), Some(1));
+        assert_eq!(replay.map_seed(1), Some(2));
+        assert_eq!(replay.map_seed(2), Some(2));
+    }
+}
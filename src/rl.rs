@@ -1,77 +1,550 @@
-use bevy::prelude::Resource;
+use std::collections::{HashMap, VecDeque};
 
+use bevy::prelude::{Entity, Resource};
+use rand::Rng;
+use rand_chacha::ChaCha12Rng;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::config_parser::{Algorithm, RLConfig};
+
+use self::{
+    checkpoint::{read_json_gz, write_json_gz},
+    ppo::RolloutBuffer,
+    sum_tree::SumTree,
+};
+
+pub mod checkpoint;
+pub mod gru;
+pub mod learner;
 pub mod model;
 pub mod model_helpers;
+pub mod ppo;
+pub mod snapshot;
+pub mod sum_tree;
+
+const MIN_PRIORITY: f32 = 1e-6;
 
-#[derive(Clone, Debug)]
+/**
+ * Result of a model update: mean loss and mean estimated value (Q-value of the taken action for
+ * DQN, state value for PPO) over the training samples
+ */
+#[derive(Default, Debug, Clone, Copy)]
+pub struct UpdateStats {
+    pub loss: f32,
+    pub mean_value: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transition {
     pub state: super::entities::AgentState,
     pub action: super::entities::Action,
-    pub reward: f32,
+    pub reward: f32, // Discounted sum of rewards over `steps`
     pub next_state: super::entities::AgentState,
+    pub done: bool,
+    pub steps: usize,
+}
+
+/**
+ * Maps by entity as lists of pairs, as JSON only has string keys
+ */
+mod entity_map {
+    use std::collections::HashMap;
+
+    use bevy::prelude::Entity;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        map: &HashMap<Entity, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Entity, V>, D::Error> {
+        Vec::<(Entity, V)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
 }
 
-#[derive(Default, Debug)]
+/**
+ * Collects the most recent transitions of each agent and merges them into n-step transitions
+ */
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct NStepAccumulator {
+    #[serde(skip)] // Set from the config when loaded
+    n: usize,
+    #[serde(skip)]
+    discount: f32,
+    #[serde(with = "entity_map")]
+    pending: HashMap<Entity, VecDeque<Transition>>,
+}
+impl NStepAccumulator {
+    fn merge(&self, transitions: &VecDeque<Transition>) -> Transition {
+        let first = transitions.front().unwrap();
+        let last = transitions.back().unwrap();
+        let mut reward = 0.0;
+        let mut discount = 1.0;
+        for t in transitions {
+            reward += discount * t.reward;
+            discount *= self.discount.powi(t.steps as i32);
+        }
+        Transition {
+            state: first.state.clone(),
+            action: first.action,
+            reward,
+            next_state: last.next_state.clone(),
+            done: last.done,
+            steps: transitions.iter().map(|t| t.steps).sum(),
+        }
+    }
+    /**
+     * Returns the transitions which are complete, i.e. have n steps or end in a terminal state
+     */
+    fn push(&mut self, e: Entity, t: Transition) -> Vec<Transition> {
+        let mut pending = self.pending.remove(&e).unwrap_or_default();
+        let done = t.done;
+        pending.push_back(t);
+        let mut complete = Vec::new();
+        if done {
+            while !pending.is_empty() {
+                complete.push(self.merge(&pending));
+                pending.pop_front();
+            }
+        } else {
+            if pending.len() >= self.n {
+                complete.push(self.merge(&pending));
+                pending.pop_front();
+            }
+            self.pending.insert(e, pending);
+        }
+        complete
+    }
+}
+
+/**
+ * Position of a stored transition in the episode of its agent
+ */
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct EpisodeLink {
+    entity: Entity,
+    step: usize,
+    previous: Option<usize>, // Slot of the previous transition, may be overwritten since
+}
+
+/**
+ * Ring buffer of transitions. If prioritized, transitions are sampled proportionally to
+ * their TD-error priorities (stored in a sum tree), otherwise uniformly.
+ */
+#[derive(Default, Debug, Serialize, Deserialize)]
 pub struct ReplayBuffer {
     pub buffer: Vec<Option<Transition>>,
     pub capacity: usize,
+    pub beta: f32,
     size: usize,
     i: usize,
+    priorities: Option<SumTree>,
+    max_priority: f32,
+    #[serde(default)] // Missing in buffers saved before the agents were
+    n_step: NStepAccumulator,
+    links: Vec<Option<EpisodeLink>>,
+    #[serde(default, with = "entity_map")]
+    episodes: HashMap<Entity, (usize, usize)>, // Slot and step of the latest transition
 }
 impl ReplayBuffer {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(cfg: &RLConfig) -> Self {
+        let capacity = cfg.replay_buffer_size;
         Self {
             buffer: vec![None; capacity],
             capacity,
+            beta: cfg.priority_beta,
             size: 0,
             i: 0,
+            priorities: cfg.prioritized_replay.then(|| SumTree::new(capacity)),
+            max_priority: 1.0,
+            n_step: NStepAccumulator {
+                n: cfg.n_step,
+                discount: cfg.discount,
+                pending: HashMap::new(),
+            },
+            links: vec![None; capacity],
+            episodes: HashMap::new(),
+        }
+    }
+    /**
+     * Adds a single step transition of agent `e`, which is stored once it is merged into an
+     * n-step transition
+     */
+    pub fn push(&mut self, e: Entity, t: Transition) {
+        let done = t.done;
+        for t in self.n_step.push(e, t) {
+            let link = match self.episodes.get(&e) {
+                Some(&(slot, step)) => EpisodeLink {
+                    entity: e,
+                    step: step + 1,
+                    previous: Some(slot),
+                },
+                None => EpisodeLink {
+                    entity: e,
+                    step: 0,
+                    previous: None,
+                },
+            };
+            self.episodes.insert(e, (self.i, link.step));
+            self.add(t, link);
+        }
+        if done {
+            self.episodes.remove(&e);
         }
     }
-    pub fn add(&mut self, t: Transition) {
+    /**
+     * Drops the incomplete n-step transitions of an agent that was removed without a terminal
+     * transition (or of all agents, on reset)
+     */
+    pub fn discard_pending(&mut self, e: Option<Entity>) {
+        if let Some(e) = e {
+            self.n_step.pending.remove(&e);
+            self.episodes.remove(&e);
+        } else {
+            self.n_step.pending.clear();
+            self.episodes.clear();
+        }
+    }
+    fn add(&mut self, t: Transition, link: EpisodeLink) {
         self.buffer[self.i] = Some(t);
+        self.links[self.i] = Some(link);
+        if let Some(priorities) = &mut self.priorities {
+            priorities.set(self.i, self.max_priority);
+        }
         if self.size < self.capacity {
             self.size += 1;
         }
         self.i = (self.i + 1) % self.capacity;
     }
-    pub fn get(&self) -> Vec<&Transition> {
-        self.buffer
-            .iter()
-            .filter_map(|x| x.as_ref())
-            .collect::<Vec<_>>()
+    /**
+     * Loads a buffer saved by a checkpoint. Its capacity must match the config.
+     */
+    pub fn load(cfg: &RLConfig, path: &str) -> Self {
+        let mut buffer: Self = read_json_gz(path);
+        assert_eq!(
+            buffer.capacity, cfg.replay_buffer_size,
+            "Replay buffer {path} does not match replay_buffer_size"
+        );
+        buffer.n_step.n = cfg.n_step;
+        buffer.n_step.discount = cfg.discount;
+        buffer
+    }
+    /**
+     * Moves the episodes of agents restored from a checkpoint to their new entities
+     */
+    pub fn remap_entities(&mut self, entities: &HashMap<Entity, Entity>) {
+        let remap = |e: Entity| entities.get(&e).copied().unwrap_or(e);
+        self.n_step.pending = self
+            .n_step
+            .pending
+            .drain()
+            .map(|(e, pending)| (remap(e), pending))
+            .collect();
+        self.episodes = self
+            .episodes
+            .drain()
+            .map(|(e, episode)| (remap(e), episode))
+            .collect();
+        for link in self.links.iter_mut().flatten() {
+            link.entity = remap(link.entity);
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    pub fn get(&self, idx: usize) -> &Transition {
+        self.buffer[idx].as_ref().expect("Empty replay buffer slot")
+    }
+    /**
+     * Returns the indices of up to `length` consecutive transitions of the same agent, ending
+     * with `idx`, in chronological order
+     */
+    pub fn sequence(&self, idx: usize, length: usize) -> Vec<usize> {
+        let mut sequence = vec![idx];
+        let mut link = self.links[idx];
+        while sequence.len() < length {
+            let Some(EpisodeLink {
+                entity,
+                step,
+                previous: Some(previous),
+            }) = link
+            else {
+                break;
+            };
+            match self.links[previous] {
+                Some(l) if l.entity == entity && l.step + 1 == step => {
+                    sequence.push(previous);
+                    link = Some(l);
+                }
+                _ => break,
+            }
+        }
+        sequence.reverse();
+        sequence
+    }
+    /**
+     * Returns the indices of `count` transitions with their importance-sampling weights
+     */
+    pub fn sample(&self, count: usize, rng: &mut ChaCha12Rng) -> Vec<(usize, f32)> {
+        if let Some(priorities) = &self.priorities {
+            // Stratified: one sample from each of `count` equal segments of the priority mass
+            let segment = priorities.total() / count as f32;
+            let selected = (0..count)
+                .map(|i| {
+                    let idx = priorities.find(segment * (i as f32 + rng.gen::<f32>()));
+                    let prob = priorities.get(idx) / priorities.total();
+                    (idx, (self.size as f32 * prob).powf(-self.beta))
+                })
+                .collect::<Vec<_>>();
+            let max_weight = selected.iter().fold(0f32, |a, (_, w)| a.max(*w));
+            selected
+                .into_iter()
+                .map(|(idx, w)| (idx, w / max_weight))
+                .collect()
+        } else if count <= self.size {
+            rand::seq::index::sample(rng, self.size, count)
+                .into_iter()
+                .map(|idx| (idx, 1.0))
+                .collect()
+        } else {
+            (0..count)
+                .map(|_| (rng.gen_range(0..self.size), 1.0))
+                .collect()
+        }
+    }
+    pub fn update_priorities(&mut self, indices: &[usize], td_errors: &[f32], alpha: f32) {
+        if let Some(priorities) = &mut self.priorities {
+            for (idx, td_error) in indices.iter().zip(td_errors) {
+                let priority = (td_error.abs() + MIN_PRIORITY).powf(alpha);
+                self.max_priority = self.max_priority.max(priority);
+                priorities.set(*idx, priority);
+            }
+        }
+    }
+}
+
+/**
+ * Experience storage of a species, depending on its learning algorithm
+ */
+#[derive(Debug)]
+pub enum Experience {
+    Replay(ReplayBuffer),
+    Rollout(RolloutBuffer),
+}
+impl Default for Experience {
+    fn default() -> Self {
+        Self::Replay(ReplayBuffer::default())
+    }
+}
+impl Experience {
+    pub fn new(cfg: &RLConfig, algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Dqn => Self::Replay(ReplayBuffer::new(cfg)),
+            Algorithm::Ppo => Self::Rollout(RolloutBuffer::default()),
+        }
+    }
+    pub fn push(&mut self, e: Entity, t: Transition) {
+        match self {
+            Self::Replay(buffer) => buffer.push(e, t),
+            Self::Rollout(rollout) => rollout.push(e, t),
+        }
+    }
+    pub fn discard_pending(&mut self, e: Option<Entity>) {
+        match self {
+            Self::Replay(buffer) => buffer.discard_pending(e),
+            Self::Rollout(rollout) => rollout.truncate(e),
+        }
+    }
+    pub fn remap_entities(&mut self, entities: &HashMap<Entity, Entity>) {
+        if let Self::Replay(buffer) = self {
+            buffer.remap_entities(entities);
+        }
+    }
+    /**
+     * On-policy storage is empty after every update, so only replay buffers are saved
+     */
+    pub fn save(&self, path: &str) {
+        if let Self::Replay(buffer) = self {
+            write_json_gz(buffer, path);
+        }
+    }
+    pub fn load(cfg: &RLConfig, algorithm: Algorithm, path: &str) -> Self {
+        match algorithm {
+            Algorithm::Dqn => Self::Replay(ReplayBuffer::load(cfg, path)),
+            Algorithm::Ppo => Self::new(cfg, algorithm),
+        }
+    }
+    /**
+     * Called after every update. On-policy transitions are only used by the update directly
+     * after they were collected.
+     */
+    pub fn finish_update(&mut self) {
+        if let Self::Rollout(rollout) = self {
+            rollout.clear();
+        }
     }
 }
 
 #[derive(Resource, Default, Debug)]
 pub struct ReplayBufferPrey {
-    pub buffer: ReplayBuffer,
+    pub buffer: Experience,
 }
 impl ReplayBufferPrey {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(cfg: &RLConfig, algorithm: Algorithm) -> Self {
         Self {
-            buffer: ReplayBuffer::new(capacity),
+            buffer: Experience::new(cfg, algorithm),
         }
     }
 }
 
 #[derive(Resource, Default, Debug)]
 pub struct ReplayBufferPredator {
-    pub buffer: ReplayBuffer,
+    pub buffer: Experience,
 }
 impl ReplayBufferPredator {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(cfg: &RLConfig, algorithm: Algorithm) -> Self {
         Self {
-            buffer: ReplayBuffer::new(capacity),
+            buffer: Experience::new(cfg, algorithm),
         }
     }
 }
 
 #[derive(Resource)]
 pub struct ModelPrey {
-    pub model: model_helpers::AgentModel,
+    pub model: learner::Learner,
 }
 
 #[derive(Resource)]
 pub struct ModelPredator {
-    pub model: model_helpers::AgentModel,
+    pub model: learner::Learner,
+}
+
+#[cfg(test)]
+mod n_step_tests {
+    use std::collections::HashMap;
+
+    use bevy::prelude::{Entity, Vec2};
+
+    use super::{NStepAccumulator, ReplayBuffer, Transition};
+    use crate::{
+        entities::{Action, AgentState},
+        helpers::map::EnvType,
+    };
+
+    fn transition(reward: f32, energy: f32, done: bool) -> Transition {
+        let state = AgentState {
+            location: Vec2::ZERO,
+            direction: 0.0,
+            speed: 0.0,
+            energy,
+            environment: EnvType::Meadow,
+            sight: Vec::new(),
+            hearing: Vec::new(),
+        };
+        Transition {
+            state: state.clone(),
+            action: Action::Walk,
+            reward,
+            next_state: AgentState {
+                energy: energy - 1.0,
+                ..state
+            },
+            done,
+            steps: 1,
+        }
+    }
+
+    fn accumulator(n: usize) -> NStepAccumulator {
+        NStepAccumulator {
+            n,
+            discount: 0.5,
+            pending: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_n_step() {
+        let mut acc = accumulator(3);
+        let e = Entity::from_raw(0);
+        assert!(acc.push(e, transition(1.0, 10.0, false)).is_empty());
+        assert!(acc.push(e, transition(2.0, 9.0, false)).is_empty());
+        let complete = acc.push(e, transition(4.0, 8.0, false));
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].reward, 1.0 + 0.5 * 2.0 + 0.25 * 4.0);
+        assert_eq!(complete[0].state.energy, 10.0);
+        assert_eq!(complete[0].next_state.energy, 7.0);
+        assert_eq!(complete[0].steps, 3);
+        assert!(!complete[0].done);
+        // Other agents are accumulated separately
+        assert!(acc
+            .push(Entity::from_raw(1), transition(1.0, 5.0, false))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_terminal_flush() {
+        let mut acc = accumulator(3);
+        let e = Entity::from_raw(0);
+        acc.push(e, transition(1.0, 10.0, false));
+        let complete = acc.push(e, transition(2.0, 9.0, true));
+        assert_eq!(complete.len(), 2);
+        assert_eq!(complete[0].reward, 2.0);
+        assert_eq!(complete[0].steps, 2);
+        assert_eq!(complete[1].reward, 2.0);
+        assert_eq!(complete[1].steps, 1);
+        assert!(complete.iter().all(|t| t.done));
+        assert!(acc.pending.is_empty());
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut buf = ReplayBuffer {
+            buffer: vec![None; 4],
+            capacity: 4,
+            n_step: accumulator(1),
+            links: vec![None; 4],
+            ..Default::default()
+        };
+        let (e1, e2) = (Entity::from_raw(0), Entity::from_raw(1));
+        buf.push(e1, transition(0.0, 10.0, false));
+        buf.push(e2, transition(0.0, 10.0, false));
+        buf.push(e1, transition(0.0, 9.0, false));
+        buf.push(e1, transition(0.0, 8.0, false));
+        assert_eq!(buf.sequence(3, 8), vec![0, 2, 3]);
+        assert_eq!(buf.sequence(3, 2), vec![2, 3]);
+        assert_eq!(buf.sequence(1, 8), vec![1]);
+        // Overwriting the first transition of e1 truncates its sequences
+        buf.push(e2, transition(0.0, 9.0, true));
+        assert_eq!(buf.sequence(3, 8), vec![2, 3]);
+        assert_eq!(buf.sequence(0, 8), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_remap_entities() {
+        let mut buf = ReplayBuffer {
+            buffer: vec![None; 4],
+            capacity: 4,
+            n_step: accumulator(2),
+            links: vec![None; 4],
+            ..Default::default()
+        };
+        let (old, new) = (Entity::from_raw(0), Entity::from_raw(5));
+        buf.push(old, transition(0.0, 10.0, false));
+        buf.push(old, transition(0.0, 9.0, false));
+        buf.push(old, transition(0.0, 8.0, false));
+        // Pending transitions and episodes survive saving and continue under the new entity
+        let mut buf: ReplayBuffer = serde_json::from_str(&serde_json::to_string(&buf).unwrap())
+            .expect("Could not parse buffer");
+        buf.n_step.n = 2;
+        buf.remap_entities(&HashMap::from([(old, new)]));
+        buf.push(new, transition(0.0, 7.0, false));
+        assert_eq!(buf.sequence(2, 8), vec![0, 1, 2]);
+        assert_eq!(buf.get(2).state.energy, 8.0);
+    }
 }
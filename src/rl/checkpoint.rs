@@ -0,0 +1,329 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use bevy::prelude::{Entity, Resource};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rand_chacha::ChaCha12Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    entities::{Agent, AgentType},
+    helpers::config_parser::{Algorithm, CheckpointConfig, Config},
+    metrics::Metrics,
+};
+
+use super::{
+    learner::Learner, model_helpers::timestamp, Experience, ReplayBufferPredator, ReplayBufferPrey,
+};
+
+const MANIFEST: &str = "manifest.toml";
+const STATE: &str = "state.toml";
+const SIMULATION: &str = "simulation.json.gz";
+
+pub fn write_json_gz<T: Serialize>(value: &T, path: &str) {
+    let file = File::create(path).unwrap_or_else(|_| panic!("Could not create {path}"));
+    serde_json::to_writer(GzEncoder::new(file, Compression::default()), value)
+        .unwrap_or_else(|_| panic!("Could not write {path}"));
+}
+
+pub fn read_json_gz<T: DeserializeOwned>(path: &str) -> T {
+    let file = File::open(path).unwrap_or_else(|_| panic!("Could not open {path}"));
+    serde_json::from_reader(BufReader::new(GzDecoder::new(file)))
+        .unwrap_or_else(|_| panic!("Could not parse {path}"))
+}
+
+/**
+ * Counters needed to continue training where a checkpoint left off
+ */
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrainingState {
+    pub update: usize,
+    pub frame: usize,
+    pub reset: usize,
+    pub map_seed: Option<u64>, // Seed of the generated map, if any
+}
+
+/**
+ * State of the simulation when a checkpoint was written, so a resumed run continues exactly like
+ * an uninterrupted one. Kept apart from `TrainingState`, as TOML has no 128 bit integers.
+ */
+#[derive(Resource, Serialize, Deserialize, Debug)]
+pub struct SimulationState {
+    pub rng: ChaCha12Rng, // Position in the stream of the simulation generator
+    pub agents: Vec<(Entity, Agent)>, // With the entities their pending transitions are stored by
+    pub food: Vec<f32>,   // Food layer of the current map
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointEntry {
+    pub step: usize,
+    pub dir: String,
+    pub timestamp: String,
+    pub prey_score: Option<f32>, // Mean reward since the previous checkpoint
+    pub predator_score: Option<f32>,
+}
+impl CheckpointEntry {
+    fn score(&self) -> f32 {
+        match (self.prey_score, self.predator_score) {
+            (None, None) => f32::NEG_INFINITY,
+            (prey, predator) => prey.unwrap_or(0.0) + predator.unwrap_or(0.0),
+        }
+    }
+}
+
+/**
+ * Checkpoints of a save path, ordered by step
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub checkpoints: Vec<CheckpointEntry>,
+}
+impl Manifest {
+    /**
+     * None if the directory has no manifest, i.e. contains only models saved by name
+     */
+    pub fn load(path: &str) -> Option<Self> {
+        let manifest = std::fs::read_to_string(format!("{path}/{MANIFEST}")).ok()?;
+        Some(toml::from_str(&manifest).expect("Could not parse checkpoint manifest"))
+    }
+    fn save(&self, path: &str) {
+        let manifest = toml::to_string(self).expect("Could not serialize checkpoint manifest");
+        std::fs::write(format!("{path}/{MANIFEST}"), manifest)
+            .expect("Could not write checkpoint manifest");
+    }
+    /**
+     * The checkpoint of the given step, or the latest one
+     */
+    pub fn select(&self, step: Option<usize>) -> &CheckpointEntry {
+        let entry = match step {
+            Some(step) => self.checkpoints.iter().find(|c| c.step == step),
+            None => self.checkpoints.last(),
+        };
+        entry.unwrap_or_else(|| {
+            let steps = self
+                .checkpoints
+                .iter()
+                .map(|c| c.step.to_string())
+                .collect::<Vec<_>>();
+            panic!(
+                "No checkpoint for step {step:?}, available steps: [{}]",
+                steps.join(", ")
+            )
+        })
+    }
+    fn insert(&mut self, entry: CheckpointEntry) {
+        self.checkpoints.retain(|c| c.step != entry.step);
+        self.checkpoints.push(entry);
+        self.checkpoints.sort_by_key(|c| c.step);
+    }
+    /**
+     * Keeps the last `keep_last` and the best `keep_best` checkpoints, returning the removed ones.
+     * Nothing is removed if both are 0.
+     */
+    fn retain(&mut self, cfg: &CheckpointConfig) -> Vec<CheckpointEntry> {
+        if cfg.keep_last == 0 && cfg.keep_best == 0 {
+            return Vec::new();
+        }
+        let mut best = self.checkpoints.iter().collect::<Vec<_>>();
+        best.sort_by(|a, b| b.score().total_cmp(&a.score()));
+        let best = best
+            .into_iter()
+            .take(cfg.keep_best)
+            .map(|c| c.step)
+            .collect::<Vec<_>>();
+        let first_last = self.checkpoints.len().saturating_sub(cfg.keep_last);
+        let (kept, removed) = self
+            .checkpoints
+            .drain(..)
+            .enumerate()
+            .partition::<Vec<_>, _>(|(i, c)| *i >= first_last || best.contains(&c.step));
+        self.checkpoints = kept.into_iter().map(|(_, c)| c).collect();
+        removed.into_iter().map(|(_, c)| c).collect()
+    }
+}
+
+/**
+ * Writes checkpoints to `step_<update>` directories of the save path and keeps its manifest.
+ * Continues the manifest of an existing save path.
+ */
+#[derive(Resource)]
+pub struct CheckpointManager {
+    path: String,
+    manifest: Manifest,
+    rewards: [(f32, usize); 2], // Prey and predator reward sums and counts since the last save
+}
+impl CheckpointManager {
+    pub fn new(config: &Config) -> Self {
+        let path = config.rl.save_path.clone();
+        Self {
+            manifest: Manifest::load(&path).unwrap_or_default(),
+            path,
+            rewards: [(0.0, 0); 2],
+        }
+    }
+    /**
+     * Adds the rewards of an update to the score of the next checkpoint
+     */
+    pub fn track(&mut self, metrics: &Metrics) {
+        for (sum, species) in self
+            .rewards
+            .iter_mut()
+            .zip([&metrics.prey, &metrics.predator])
+        {
+            sum.0 += species.reward_sum;
+            sum.1 += species.rewards;
+        }
+    }
+    pub fn save(
+        &mut self,
+        config: &Config,
+        models: (&Learner, &Learner),
+        buffers: (&Experience, &Experience),
+        state: TrainingState,
+        simulation: &SimulationState,
+    ) {
+        let dir = format!("step_{:08}", state.update);
+        let path = format!("{}/{dir}", self.path);
+        std::fs::create_dir_all(&path).expect("Could not create checkpoint directory");
+        models.0.save(&path, "prey", config);
+        models.1.save(&path, "predator", config);
+        buffers.0.save(&format!("{path}/prey_buffer.json.gz"));
+        buffers.1.save(&format!("{path}/predator_buffer.json.gz"));
+        let state_string = toml::to_string(&state).expect("Could not serialize training state");
+        std::fs::write(format!("{path}/{STATE}"), state_string)
+            .expect("Could not write training state");
+        write_json_gz(simulation, &format!("{path}/{SIMULATION}"));
+
+        let score = |(sum, count): (f32, usize)| (count > 0).then(|| sum / count as f32);
+        self.manifest.insert(CheckpointEntry {
+            step: state.update,
+            dir,
+            timestamp: timestamp(),
+            prey_score: score(self.rewards[0]),
+            predator_score: score(self.rewards[1]),
+        });
+        self.rewards = [(0.0, 0); 2];
+        for removed in self.manifest.retain(&config.rl.checkpoints) {
+            std::fs::remove_dir_all(format!("{}/{}", self.path, removed.dir))
+                .expect("Could not remove checkpoint");
+        }
+        self.manifest.save(&self.path);
+    }
+}
+
+/**
+ * Directory and step of the checkpoint selected by `load_path` and `load_step`, if the load path
+ * has a manifest
+ */
+pub fn selected_checkpoint(config: &Config) -> Option<(String, usize)> {
+    let path = config.rl.load_path.as_ref()?;
+    let manifest = Manifest::load(path)?;
+    let entry = manifest.select(config.rl.load_step);
+    Some((format!("{path}/{}", entry.dir), entry.step))
+}
+
+/**
+ * Model of a species from a checkpoint directory (its latest checkpoint or `load_step`), or from
+ * the directory of a single checkpoint
+ */
+pub fn load_learner(path: &str, agent_type: AgentType, config: &Config) -> Learner {
+    let name = match agent_type {
+        AgentType::Prey => "prey",
+        AgentType::Predator => "predator",
+    };
+    match Manifest::load(path) {
+        Some(manifest) => {
+            let entry = manifest.select(config.rl.load_step);
+            Learner::load(&format!("{path}/{}", entry.dir), name, config)
+        }
+        None => Learner::load(path, name, config),
+    }
+}
+
+/**
+ * Buffers, counters and simulation state of the selected checkpoint, for `resume`. Buffers are
+ * stored for the algorithms of the loaded models, which may differ from the configured ones.
+ * Checkpoints written before the simulation state was saved have none.
+ */
+pub fn load_training(
+    config: &Config,
+    prey_algorithm: Algorithm,
+    predator_algorithm: Algorithm,
+) -> (
+    ReplayBufferPrey,
+    ReplayBufferPredator,
+    TrainingState,
+    Option<SimulationState>,
+) {
+    let (path, _) = selected_checkpoint(config)
+        .expect("Resuming requires a load path with a checkpoint manifest");
+    let state =
+        std::fs::read_to_string(format!("{path}/{STATE}")).expect("Could not read training state");
+    let state = toml::from_str(&state).expect("Could not parse training state");
+    let buffer = |name: &str, algorithm| {
+        let buffer_path = format!("{path}/{name}_buffer.json.gz");
+        if Path::new(&buffer_path).exists() {
+            Experience::load(&config.rl, algorithm, &buffer_path)
+        } else {
+            Experience::new(&config.rl, algorithm)
+        }
+    };
+    (
+        ReplayBufferPrey {
+            buffer: buffer("prey", prey_algorithm),
+        },
+        ReplayBufferPredator {
+            buffer: buffer("predator", predator_algorithm),
+        },
+        state,
+        Path::new(&format!("{path}/{SIMULATION}"))
+            .exists()
+            .then(|| read_json_gz(&format!("{path}/{SIMULATION}"))),
+    )
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use super::*;
+
+    #[test]
+    fn test_retain() {
+        let mut manifest = Manifest::default();
+        for (step, score) in [(10, 1.0), (20, 5.0), (30, 2.0), (40, 4.0), (50, 0.0)] {
+            manifest.insert(CheckpointEntry {
+                step,
+                dir: format!("step_{step}"),
+                timestamp: String::new(),
+                prey_score: Some(score),
+                predator_score: None,
+            });
+        }
+        let cfg = CheckpointConfig {
+            keep_last: 2,
+            keep_best: 2,
+            resume: false,
+        };
+        let removed = manifest.retain(&cfg);
+        let steps =
+            |entries: &[CheckpointEntry]| entries.iter().map(|c| c.step).collect::<Vec<_>>();
+        assert_eq!(steps(&manifest.checkpoints), vec![20, 40, 50]);
+        assert_eq!(steps(&removed), vec![10, 30]);
+        assert_eq!(manifest.select(None).step, 50);
+        assert_eq!(manifest.select(Some(20)).step, 20);
+
+        // Only the best ones with keep_last = 0, all of them if neither is set
+        let cfg = CheckpointConfig {
+            keep_last: 0,
+            keep_best: 1,
+            resume: false,
+        };
+        assert_eq!(steps(&manifest.retain(&cfg)), vec![40, 50]);
+        assert_eq!(steps(&manifest.checkpoints), vec![20]);
+        let cfg = CheckpointConfig {
+            keep_last: 0,
+            keep_best: 0,
+            resume: false,
+        };
+        assert!(manifest.retain(&cfg).is_empty());
+        assert_eq!(steps(&manifest.checkpoints), vec![20]);
+    }
+}
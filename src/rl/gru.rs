@@ -0,0 +1,60 @@
+use burn::module::{Module, Param};
+use burn::nn;
+use burn::tensor::activation::sigmoid;
+use burn::tensor::backend::Backend;
+use burn::tensor::Tensor;
+
+/**
+ * Single step of a gated recurrent unit (burn does not provide recurrent layers):
+ *
+ * r = σ(W_r x + U_r h), z = σ(W_z x + U_z h)
+ * n = tanh(W_n x + r * (U_n h))
+ * h' = (1 - z) * n + z * h
+ *
+ * The gates are computed by one input and one hidden projection of width 3 * hidden_size.
+ */
+#[derive(Module, Debug)]
+pub struct GruCell<B: Backend<FloatElem = f32>> {
+    input: Param<nn::Linear<B>>,
+    hidden: Param<nn::Linear<B>>,
+    hidden_size: usize,
+}
+impl<B: Backend<FloatElem = f32>> GruCell<B> {
+    pub fn new(input_size: usize, hidden_size: usize) -> Self {
+        Self {
+            input: Param::from(nn::Linear::new(
+                &nn::LinearConfig::new(input_size, 3 * hidden_size).with_bias(true),
+            )),
+            hidden: Param::from(nn::Linear::new(
+                &nn::LinearConfig::new(hidden_size, 3 * hidden_size).with_bias(true),
+            )),
+            hidden_size,
+        }
+    }
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    /**
+     * Returns the new hidden state, `xs` has shape `[batch, input]`, `hs` `[batch, hidden]`
+     */
+    pub fn forward(&self, xs: Tensor<B, 2>, hs: Tensor<B, 2>) -> Tensor<B, 2> {
+        let [batch_size, _] = xs.dims();
+        let size = self.hidden_size;
+        let gates_x = self.input.forward(xs);
+        let gates_h = self.hidden.forward(hs.clone());
+        let gate = |gates: &Tensor<B, 2>, i: usize| {
+            gates
+                .clone()
+                .index([0..batch_size, i * size..(i + 1) * size])
+        };
+        let reset = sigmoid(gate(&gates_x, 0).add(gate(&gates_h, 0)));
+        let update = sigmoid(gate(&gates_x, 1).add(gate(&gates_h, 1)));
+        let candidate = gate(&gates_x, 2).add(reset.mul(gate(&gates_h, 2))).tanh();
+        update
+            .clone()
+            .neg()
+            .add_scalar(1.0)
+            .mul(candidate)
+            .add(update.mul(hs))
+    }
+}
@@ -0,0 +1,150 @@
+use bevy::prelude::Vec2;
+use rand_chacha::ChaCha12Rng;
+use serde::Deserialize;
+
+use crate::{
+    entities::{Action, AgentState, AgentType},
+    helpers::config_parser::{AgentConfig, Algorithm, Config, RLConfig},
+};
+
+use super::{
+    model::NormalizationData, model_helpers::AgentModel, ppo::PPOModel, Experience, UpdateStats,
+};
+
+/**
+ * Only the algorithm of a saved model description, which is missing in DQN descriptions
+ */
+#[derive(Deserialize)]
+struct DescriptionAlgorithm {
+    #[serde(default)]
+    algorithm: Algorithm,
+}
+
+/**
+ * Model of a species, trained either by DQN (off-policy, replay buffer) or PPO (on-policy,
+ * rollouts)
+ */
+pub enum Learner {
+    Dqn(AgentModel),
+    Ppo(PPOModel),
+}
+impl Learner {
+    pub fn new(
+        inputs: usize,
+        outputs: usize,
+        cfg: &RLConfig,
+        algorithm: Algorithm,
+        agent_type: AgentType,
+    ) -> Self {
+        match algorithm {
+            Algorithm::Dqn => Self::Dqn(AgentModel::new(
+                inputs,
+                outputs,
+                &cfg.layers,
+                cfg.learning_rate,
+                cfg.dueling,
+                cfg.recurrent.then_some(cfg.recurrent_size),
+                agent_type,
+            )),
+            Algorithm::Ppo => Self::Ppo(PPOModel::new(
+                inputs,
+                outputs,
+                &cfg.layers,
+                cfg.learning_rate,
+                agent_type,
+            )),
+        }
+    }
+    pub fn load(path: &str, model_name: &str, config: &Config) -> Self {
+        let cfg_string = std::fs::read_to_string(format!("{path}/{model_name}.toml"))
+            .expect("Could not read cfg file");
+        let cfg: DescriptionAlgorithm =
+            toml::from_str(&cfg_string).expect("Could not parse cfg file");
+        match cfg.algorithm {
+            Algorithm::Dqn => Self::Dqn(AgentModel::load(path, model_name, config)),
+            Algorithm::Ppo => Self::Ppo(PPOModel::load(path, model_name, config)),
+        }
+    }
+    /**
+     * Writes the model, its description, its training state and a snapshot of the config, all
+     * named `name`
+     */
+    pub fn save(&self, path: &str, name: &str, config: &Config) {
+        match self {
+            Self::Dqn(model) => model.save(path, name, config),
+            Self::Ppo(model) => model.save(path, name, config),
+        }
+    }
+    /**
+     * Algorithm the model is trained with, which is that of its description if it was loaded
+     */
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Dqn(_) => Algorithm::Dqn,
+            Self::Ppo(_) => Algorithm::Ppo,
+        }
+    }
+    /**
+     * Exploration rate of epsilon-greedy models
+     */
+    pub fn eps(&self) -> Option<f32> {
+        match self {
+            Self::Dqn(model) => Some(model.eps),
+            Self::Ppo(_) => None,
+        }
+    }
+    /**
+     * Always takes the best action. Policies of PPO models are not sampled unless learning.
+     */
+    pub fn disable_exploration(&mut self) {
+        if let Self::Dqn(model) = self {
+            model.eps = 0.0;
+        }
+    }
+    pub fn reset_target(&mut self) {
+        if let Self::Dqn(model) = self {
+            model.reset_target();
+        }
+    }
+    pub fn train(
+        &mut self,
+        experience: &mut Experience,
+        norm: &NormalizationData,
+        cfg: &RLConfig,
+        rng: &mut ChaCha12Rng,
+    ) -> UpdateStats {
+        match (self, experience) {
+            (Self::Dqn(model), Experience::Replay(buffer)) => {
+                model.backpropagate(buffer, norm, cfg, rng)
+            }
+            (Self::Ppo(model), Experience::Rollout(rollout)) => {
+                model.train(rollout, norm, cfg, rng)
+            }
+            _ => panic!("Model and experience storage of different algorithms"),
+        }
+    }
+    /**
+     * Actions of all agents of the species from one batched forward pass. Also returns the next
+     * hidden states, which are empty for feed-forward models.
+     */
+    pub fn get_actions(
+        &self,
+        states: &[&AgentState],
+        hidden: &[&[f32]],
+        config: &AgentConfig,
+        world_limits: (Vec2, Vec2),
+        learning: bool,
+        rngs: &mut [&mut ChaCha12Rng],
+    ) -> Vec<(Action, Vec<f32>)> {
+        match self {
+            Self::Dqn(model) => {
+                model.get_actions(states, hidden, config, world_limits, learning, rngs)
+            }
+            Self::Ppo(model) => model
+                .get_actions(states, config, world_limits, learning, rngs)
+                .into_iter()
+                .map(|action| (action, Vec::new()))
+                .collect(),
+        }
+    }
+}
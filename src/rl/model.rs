@@ -1,6 +1,8 @@
 use bevy::prelude::Vec2;
 use burn::module::Module;
 use burn::module::Param;
+use burn::module::ParamId;
+use burn::module::State;
 use burn::optim::Optimizer;
 use burn::tensor::Data;
 use burn::tensor::Shape;
@@ -8,8 +10,10 @@ use burn::tensor::Tensor;
 use burn::train::TrainOutput;
 use burn::train::TrainStep;
 use burn_ndarray::NdArrayBackend;
-use rand::rngs::ThreadRng;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use rand::Rng;
+use rand_chacha::ChaCha12Rng;
 
 use burn::nn;
 use burn::tensor::backend::{ADBackend, Backend};
@@ -18,62 +22,157 @@ use crate::entities::Action;
 
 use crate::entities::raycast::Detection;
 use crate::entities::AgentState;
+use crate::helpers::config_parser::AgentConfig;
+
+use super::gru::GruCell;
 
 pub type ModelBackend = NdArrayBackend<f32>;
 
 pub struct TrainModelInput<B: Backend, const D: usize> {
     pub outputs: Tensor<B, D>,
     pub targets: Tensor<B, D>,
+    pub weights: Tensor<B, D>,
 }
 
 #[derive(Module, Debug)]
 pub struct Model<B: Backend<FloatElem = f32>> {
     pub lr: f32,
+    pub dueling: bool,
     hidden_layers: Param<Vec<nn::Linear<B>>>,
+    recurrent: Param<Vec<GruCell<B>>>, // Empty for feed-forward models
     output_layer: Param<nn::Linear<B>>,
     hidden_activation: nn::ReLU,
 }
 impl<B: Backend<FloatElem = f32>> Model<B> {
-    pub fn new(inputs: usize, outputs: usize, hidden_layers: &[usize], lr: f32) -> Self {
+    /**
+     * With `dueling`, the output layer has an additional unit for the state value, which is
+     * combined with the remaining units (advantages) into Q-values. With `recurrent`, a GRU
+     * of the given size is placed between the hidden layers and the output layer.
+     */
+    pub fn new(
+        inputs: usize,
+        outputs: usize,
+        hidden_layers: &[usize],
+        lr: f32,
+        dueling: bool,
+        recurrent: Option<usize>,
+    ) -> Self {
         let layers = [&[inputs], hidden_layers].concat();
         let mut hidden_layers: Vec<nn::Linear<B>> = Vec::with_capacity(hidden_layers.len() + 1);
         for w in layers.windows(2) {
             let l = nn::Linear::new(&nn::LinearConfig::new(w[0], w[1]).with_bias(true));
             hidden_layers.push(l);
         }
+        let recurrent = recurrent
+            .map(|size| GruCell::new(*layers.last().unwrap(), size))
+            .into_iter()
+            .collect::<Vec<_>>();
+        let last_size = recurrent
+            .first()
+            .map_or(*layers.last().unwrap(), |gru| gru.hidden_size());
         let l = nn::Linear::new(
-            &nn::LinearConfig::new(*layers.last().unwrap(), outputs).with_bias(true),
+            &nn::LinearConfig::new(last_size, outputs + dueling as usize).with_bias(true),
         );
         let output_layer = l;
 
-        Self {
+        let model = Self {
             lr,
+            dueling,
             hidden_layers: Param::from(hidden_layers),
+            recurrent: Param::from(recurrent),
             output_layer: Param::from(output_layer),
             hidden_activation: nn::ReLU::default(),
-        }
+        };
+        let mut state = model.state();
+        set_path_param_ids(&mut state, "model");
+        model
+            .load(&state)
+            .expect("Could not reassign parameter ids")
     }
 
-    pub fn forward(&self, mut xs: Tensor<B, 2>) -> Tensor<B, 2> {
+    pub fn recurrent_size(&self) -> Option<usize> {
+        self.recurrent.first().map(|gru| gru.hidden_size())
+    }
+
+    /**
+     * Also returns the next hidden state of recurrent models. A missing hidden state is
+     * initialized to zeros.
+     */
+    pub fn forward(
+        &self,
+        mut xs: Tensor<B, 2>,
+        hs: Option<Tensor<B, 2>>,
+    ) -> (Tensor<B, 2>, Option<Tensor<B, 2>>) {
         for layer in self.hidden_layers.iter() {
             xs = layer.forward(xs);
             xs = self.hidden_activation.forward(xs);
         }
+        let hs = self.recurrent.first().map(|gru| {
+            let hs = hs.unwrap_or_else(|| Tensor::zeros([xs.dims()[0], gru.hidden_size()]));
+            gru.forward(xs.clone(), hs)
+        });
+        if let Some(hs) = &hs {
+            xs = hs.clone();
+        }
         xs = self.output_layer.forward(xs);
-        xs
+        if self.dueling {
+            let [batch_size, size] = xs.dims();
+            let outputs = size - 1;
+            let advantages = xs.clone().index([0..batch_size, 0..outputs]);
+            let value = xs.index([0..batch_size, outputs..size]);
+            let mean_advantage = advantages.clone().mean_dim(1);
+            xs = value
+                .repeat(1, outputs)
+                .add(advantages)
+                .sub(mean_advantage.repeat(1, outputs));
+        }
+        (xs, hs)
     }
 
-    pub fn loss(outputs: Tensor<B, 2>, targets: Tensor<B, 2>) -> Tensor<B, 2> {
+    /**
+     * Per-sample squared error, scaled by the importance-sampling weights (shape `[batch, 1]`)
+     */
+    pub fn loss(
+        outputs: Tensor<B, 2>,
+        targets: Tensor<B, 2>,
+        weights: Tensor<B, 2>,
+    ) -> Tensor<B, 2> {
         let batch_size = outputs.dims()[0];
-        let loss = outputs.sub(targets).powf(2.0).mean_dim(1);
+        let loss = outputs.sub(targets).powf(2.0).mean_dim(1).mul(weights);
         debug_assert!(loss.dims()[0] == batch_size);
         loss
     }
 
     pub fn save_model(&self, path: &str) {
-        self.state().save(path).unwrap();
+        save_state(&self.state(), path);
+    }
+}
+/**
+ * Same format as State::save, but serde_json::Value sorts the keys of burn's HashMaps, so
+ * identical models are written to identical files
+ */
+pub fn save_state(state: &State<f32>, path: &str) {
+    let state = serde_json::to_value(state).expect("Could not serialize model");
+    let file = std::fs::File::create(path).expect("Could not create model file");
+    serde_json::to_writer(GzEncoder::new(file, Compression::default()), &state)
+        .expect("Could not write model file");
+}
+/**
+ * Replaces the randomly generated parameter ids with ids derived from the parameter paths,
+ * which burn does not allow to be seeded
+ */
+pub fn set_path_param_ids(state: &mut State<f32>, path: &str) {
+    match state {
+        State::StateNamed(named) => {
+            for (name, value) in named.values.iter_mut() {
+                set_path_param_ids(value, &format!("{path}.{name}"));
+            }
+        }
+        State::ParamId(id) => *id = ParamId::from(path),
+        State::Data(_) => {}
     }
 }
+
 impl<B: ADBackend<FloatElem = f32>> Model<B> {
     pub fn train_step<O: Optimizer<Backend = B>>(
         &self,
@@ -87,7 +186,7 @@ impl<B: ADBackend<FloatElem = f32>> Model<B> {
 }
 impl<B: ADBackend<FloatElem = f32>> TrainStep<TrainModelInput<B, 2>, Tensor<B, 2>> for Model<B> {
     fn step(&self, item: TrainModelInput<B, 2>) -> TrainOutput<Tensor<B, 2>> {
-        let loss = Self::loss(item.outputs.clone(), item.targets);
+        let loss = Self::loss(item.outputs.clone(), item.targets, item.weights);
         TrainOutput::new(self, loss.backward(), item.outputs)
     }
 }
@@ -102,8 +201,43 @@ pub struct NormalizationData {
     pub min_dist: f32,
     pub max_dist: f32,
 }
+impl NormalizationData {
+    pub fn new(config: &AgentConfig, world_limits: (Vec2, Vec2)) -> Self {
+        Self {
+            min_speed: 0.0,
+            max_speed: config.run_speed,
+            min_loc: world_limits.0,
+            max_loc: world_limits.1,
+            min_energy: 0.0,
+            max_energy: 100.0,
+            min_dist: 0.0,
+            max_dist: config.vision_range,
+        }
+    }
+}
 
 pub fn state_to_tensor<B: Backend>(state: &AgentState, norm: &NormalizationData) -> Tensor<B, 2> {
+    let data = state_to_data(state, norm);
+    let size = data.len();
+    Tensor::from_floats(Data::new(data, Shape::from([1, size])))
+}
+
+/**
+ * One row per state
+ */
+pub fn states_to_tensor<B: Backend>(
+    states: &[&AgentState],
+    norm: &NormalizationData,
+) -> Tensor<B, 2> {
+    let rows = states
+        .iter()
+        .map(|state| state_to_data(state, norm))
+        .collect::<Vec<_>>();
+    let size = rows[0].len();
+    Tensor::from_floats(Data::new(rows.concat(), Shape::from([states.len(), size])))
+}
+
+fn state_to_data(state: &AgentState, norm: &NormalizationData) -> Vec<f32> {
     let AgentState {
         location,
         direction,
@@ -140,9 +274,7 @@ pub fn state_to_tensor<B: Backend>(state: &AgentState, norm: &NormalizationData)
         } else if let Detection::PreyDead(en) = det.detection {
             data[offset + 1 + 5] = (en - norm.min_energy) / (norm.max_energy - norm.min_energy);
         }
-        if det.food {
-            data[offset + 1 + 8] = 1.0;
-        }
+        data[offset + 1 + 8] = det.food;
         data[offset + 1 + 9 + det.env.get_index()] = 1.0;
     }
     for (i, det) in hearing.iter().enumerate() {
@@ -160,34 +292,39 @@ pub fn state_to_tensor<B: Backend>(state: &AgentState, norm: &NormalizationData)
         } else if let Detection::PreyDead(en) = det.detection {
             data[offset + 1 + 5] = (en - norm.min_energy) / (norm.max_energy - norm.min_energy);
         }
-        if det.food {
-            data[offset + 1 + 8] = 1.0;
+        data[offset + 1 + 8] = det.food;
+    }
+    data
+}
+
+pub fn argmax(data: &[f32]) -> usize {
+    let mut max_idx = 0;
+    let mut max_val = data[0];
+    for (i, val) in data.iter().enumerate() {
+        if *val > max_val {
+            max_idx = i;
+            max_val = *val;
         }
     }
-    Tensor::from_floats(Data::new(data, Shape::from([1, size])))
+    max_idx
 }
 
-pub fn tensor_to_action<B: Backend<FloatElem = f32>>(
-    tensor: &Tensor<B, 2>,
+/**
+ * Epsilon-greedy action from one row of model outputs
+ */
+pub fn output_to_action(
+    output: &[f32],
     explore_prob: f32,
     learning: bool,
-    rng: &mut ThreadRng,
+    rng: &mut ChaCha12Rng,
 ) -> Action {
     let size = 2 + 1 + 2 + 1 + 2 + 1 + 1 + 1;
-    let data: Vec<f32> = tensor.to_data().value;
-    assert!(data.len() == size);
+    assert!(output.len() == size);
     if learning && rng.gen::<f32>() < explore_prob {
         let action_idx = rng.gen_range(0..size);
         Action::from_action_index(action_idx)
     } else {
-        let mut max_idx = 0;
-        let mut max_val = data[0];
-        for (i, val) in data.iter().enumerate() {
-            if *val > max_val {
-                max_idx = i;
-                max_val = *val;
-            }
-        }
+        let max_idx = argmax(output);
         // TODO: remove
         if rng.gen::<f32>() < explore_prob {
             let action_idx = rng.gen_range(0..8);
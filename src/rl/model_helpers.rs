@@ -1,24 +1,27 @@
 use bevy::prelude::Vec2;
 use burn::{
     module::{Module, State},
-    optim::{Adam, AdamConfig},
-    tensor::{Data, Shape, Tensor},
+    optim::{Adam, AdamConfig, Optimizer},
+    tensor::{backend::Backend, Data, Shape, Tensor},
 };
 use burn_autodiff::ADBackendDecorator;
-use rand::rngs::ThreadRng;
+use rand_chacha::ChaCha12Rng;
 use serde::{Deserialize, Serialize};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
     entities::{Action, AgentState, AgentType},
-    helpers::config_parser::{AgentConfig, RLConfig},
+    helpers::config_parser::{AgentConfig, Config, RLConfig},
     rl::model::TrainModelInput,
 };
 
 use super::{
-    model::{state_to_tensor, tensor_to_action, Model, ModelBackend, NormalizationData},
-    Transition,
+    model::{
+        output_to_action, save_state, states_to_tensor, Model, ModelBackend, NormalizationData,
+    },
+    snapshot::{check_compatibility, save_snapshot},
+    ReplayBuffer, UpdateStats,
 };
 #[derive(Serialize, Deserialize)]
 struct ModelDescription {
@@ -27,6 +30,9 @@ struct ModelDescription {
     counter: usize,
     layers: Vec<usize>,
     agent_type: String,
+    #[serde(default)]
+    dueling: bool,
+    recurrent: Option<usize>,
 }
 impl ModelDescription {
     pub fn from_agent_model(am: &AgentModel) -> Self {
@@ -39,6 +45,8 @@ impl ModelDescription {
                 AgentType::Prey => "prey".to_string(),
                 AgentType::Predator => "predator".to_string(),
             },
+            dueling: am.model.dueling,
+            recurrent: am.model.recurrent_size(),
         }
     }
 }
@@ -58,9 +66,11 @@ impl AgentModel {
         outputs: usize,
         hidden_layers: &[usize],
         lr: f32,
+        dueling: bool,
+        recurrent: Option<usize>,
         agent_type: AgentType,
     ) -> Self {
-        let model = Model::new(inputs, outputs, hidden_layers, lr);
+        let model = Model::new(inputs, outputs, hidden_layers, lr, dueling, recurrent);
         Self {
             eps: 1.0,
             model: model.clone(),
@@ -73,52 +83,105 @@ impl AgentModel {
     }
     pub fn backpropagate(
         &mut self,
-        transitions: &[&Transition],
+        buffer: &mut ReplayBuffer,
         norm: &NormalizationData,
         cfg: &RLConfig,
-        rng: &mut ThreadRng,
-    ) -> f32 {
+        rng: &mut ChaCha12Rng,
+    ) -> UpdateStats {
+        if buffer.is_empty() {
+            return UpdateStats::default();
+        }
         let mut loss_sum = 0f32;
+        let mut q_sum = 0f32;
         let num_batches = cfg.sample_count / cfg.batch_size;
         let to_choose = num_batches * cfg.batch_size;
-        // println!("Length: {}, amount: {}", transitions.len(), to_choose);
-        let selected_transition_indices =
-            rand::seq::index::sample(rng, 10 * transitions.len() - 1, to_choose)
-                .into_iter()
-                .map(|el| el % transitions.len())
+        let selected_transitions = buffer.sample(to_choose, rng);
+        // Recurrent models are unrolled over the preceding transitions of the same agent
+        let sequence_length = if self.model.recurrent_size().is_some() {
+            cfg.sequence_length
+        } else {
+            1
+        };
+        for batch in selected_transitions.chunks(cfg.batch_size) {
+            let batch_size = batch.len();
+            let num_actions = *self.layers.last().unwrap();
+            let transitions = batch
+                .iter()
+                .map(|(idx, _)| buffer.get(*idx))
                 .collect::<Vec<_>>();
-        let mut selected_transitions = Vec::with_capacity(to_choose);
-        for i in selected_transition_indices {
-            selected_transitions.push(transitions[i].clone());
-        }
-        for i in 0..num_batches {
-            let mut outputs: Vec<Tensor<ADBackendDecorator<ModelBackend>, 2>> =
-                Vec::with_capacity(cfg.batch_size);
-            let mut targets: Vec<Tensor<ADBackendDecorator<ModelBackend>, 2>> =
-                Vec::with_capacity(cfg.batch_size);
-            for j in 0..cfg.batch_size {
-                let transition = &selected_transitions[i * cfg.batch_size + j];
-                let output = self.model.forward(state_to_tensor(&transition.state, norm));
-                let mut output_vec = output.to_data().value;
-                outputs.push(output);
-                let new_state_outputs = self
-                    .target
-                    .forward(state_to_tensor(&transition.next_state, norm))
-                    .to_data()
-                    .value;
-                assert!(new_state_outputs.len() == *self.layers.last().unwrap());
-                let ns_target = new_state_outputs
+            let sequences = batch
+                .iter()
+                .map(|(idx, _)| buffer.sequence(*idx, sequence_length))
+                .collect::<Vec<_>>();
+            let column = |values: Vec<f32>| -> Tensor<ADBackendDecorator<ModelBackend>, 2> {
+                Tensor::from_floats(Data::new(values, Shape::from([batch_size, 1])))
+            };
+
+            let (boutputs, hs) = unroll(&self.model, &sequences, buffer, norm);
+            let actions: Tensor<ADBackendDecorator<ModelBackend>, 2> =
+                Tensor::from_floats(Data::new(
+                    transitions
+                        .iter()
+                        .flat_map(|t| {
+                            let mut one_hot = vec![0.0; num_actions];
+                            one_hot[t.action.to_action_index()] = 1.0;
+                            one_hot
+                        })
+                        .collect(),
+                    Shape::from([batch_size, num_actions]),
+                ));
+
+            // Targets are computed on detached tensors, as the target network shares parameter
+            // ids with the online network
+            let next_states = states_to_tensor(
+                &transitions
                     .iter()
-                    .fold(f32::NEG_INFINITY, |a, b| a.max(*b));
-                output_vec[transition.action.to_action_index()] =
-                    transition.reward + cfg.discount * ns_target;
-                let out_len = output_vec.len();
-                let target = Tensor::from_floats(Data::new(output_vec, Shape::from([1, out_len])));
-                targets.push(target);
-            }
-            let boutputs = Tensor::cat(outputs, 0);
-            let btargets = Tensor::cat(targets, 0);
-            loss_sum += Model::loss(boutputs.clone(), btargets.clone())
+                    .map(|t| &t.next_state)
+                    .collect::<Vec<_>>(),
+                norm,
+            );
+            let target_hs = match hs {
+                Some(_) => unroll(&self.target, &sequences, buffer, norm).1,
+                None => None,
+            };
+            let next_outputs = self
+                .target
+                .forward(next_states.clone(), target_hs)
+                .0
+                .detach();
+            let next_actions = if cfg.double_dqn {
+                // Actions selected by the online network, evaluated by the target network
+                self.model.forward(next_states, hs).0.argmax(1)
+            } else {
+                next_outputs.clone().argmax(1)
+            };
+            let bootstrap = transitions
+                .iter()
+                .map(|t| {
+                    if t.done {
+                        0.0
+                    } else {
+                        cfg.discount.powi(t.steps as i32)
+                    }
+                })
+                .collect();
+            let target_values = column(transitions.iter().map(|t| t.reward).collect())
+                .add(column(bootstrap).mul(next_outputs.index_select(next_actions)))
+                .detach();
+
+            let detached = boutputs.clone().detach();
+            let taken = detached.clone().mul(actions.clone()).sum_dim(1);
+            q_sum += taken.clone().sum().single_value();
+            let td_errors = target_values.clone().sub(taken).to_data().value;
+            // Only the taken action is moved towards its target
+            let btargets = detached
+                .mul(actions.clone().neg().add_scalar(1.0))
+                .add(target_values.repeat(1, num_actions).mul(actions));
+            let bweights = Tensor::from_floats(Data::new(
+                batch.iter().map(|(_, w)| *w).collect(),
+                Shape::from([batch.len(), 1]),
+            ));
+            loss_sum += Model::loss(boutputs.clone(), btargets.clone(), bweights.clone())
                 .sum()
                 .single_value();
             self.model = self.model.train_step(
@@ -126,32 +189,44 @@ impl AgentModel {
                 TrainModelInput {
                     outputs: boutputs,
                     targets: btargets,
+                    weights: bweights,
                 },
             );
+            let indices = batch.iter().map(|(idx, _)| *idx).collect::<Vec<_>>();
+            buffer.update_priorities(&indices, &td_errors, cfg.priority_alpha);
         }
+        buffer.beta = (buffer.beta + cfg.priority_beta_step).min(1.0);
         self.eps -= cfg.eps_step;
         if self.eps < cfg.eps_min {
             self.eps = cfg.eps_min;
         }
-        loss_sum / to_choose as f32
+        UpdateStats {
+            loss: loss_sum / to_choose as f32,
+            mean_value: q_sum / to_choose as f32,
+        }
     }
-    pub fn save(&self, path: &str) {
-        let filename = {
-            let start = SystemTime::now();
-            let since_the_epoch = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
-            since_the_epoch.as_millis().to_string()
-        };
+    /**
+     * Writes `<name>.toml`, `.model`, `.config.toml` and the training state of the model: the
+     * optimizer (`.optim`) and the target network (`.target`)
+     */
+    pub fn save(&self, path: &str, name: &str, config: &Config) {
         let cfg = ModelDescription::from_agent_model(self);
         let toml_cfg_string = toml::to_string(&cfg).expect("Could not serialize model description");
-        let at = cfg.agent_type;
-        std::fs::write(format!("{path}/{at}_{filename}.toml"), toml_cfg_string)
+        std::fs::write(format!("{path}/{name}.toml"), toml_cfg_string)
             .expect("Could not write model description");
-        self.model
-            .save_model(&format!("{path}/{at}_{filename}.model"));
+        save_snapshot(&format!("{path}/{name}"), config, self.agent_type);
+        self.model.save_model(&format!("{path}/{name}.model"));
+        self.target.save_model(&format!("{path}/{name}.target"));
+        save_state(
+            &self.opt.state(&self.model),
+            &format!("{path}/{name}.optim"),
+        );
     }
-    pub fn load(path: &str, model_name: &str, lr: f32) -> Self {
+    /**
+     * Loads a saved model, panicking if its sensors or actions do not match the config
+     */
+    pub fn load(path: &str, model_name: &str, config: &Config) -> Self {
+        let lr = config.rl.learning_rate;
         let cfg_string = std::fs::read_to_string(format!("{path}/{model_name}.toml"))
             .expect("Could not read cfg file");
         let cfg: ModelDescription = toml::from_str(&cfg_string).expect("Could not parse cfg file");
@@ -160,12 +235,21 @@ impl AgentModel {
             cfg.layers[cfg.layers.len() - 1],
             &cfg.layers[1..cfg.layers.len() - 1],
             cfg.lr,
+            cfg.dueling,
+            cfg.recurrent,
             match cfg.agent_type.as_str() {
                 "prey" => AgentType::Prey,
                 "predator" => AgentType::Predator,
                 _ => panic!("Unknown agent type"),
             },
         );
+        check_compatibility(
+            &format!("{path}/{model_name}"),
+            config,
+            model.agent_type,
+            cfg.layers[0],
+            cfg.layers[cfg.layers.len() - 1],
+        );
         model.eps = cfg.eps;
         model.counter = cfg.counter;
         model.model = model
@@ -175,31 +259,129 @@ impl AgentModel {
         if model.model.lr != lr {
             model.model.lr = lr;
         }
+        // Older models were saved without their training state
+        model.target = match State::load(format!("{path}/{model_name}.target").as_str()) {
+            Ok(state) => model.model.clone().load(&state).unwrap(),
+            Err(_) => model.model.clone(),
+        };
+        if let Ok(state) = State::load(format!("{path}/{model_name}.optim").as_str()) {
+            model
+                .opt
+                .load(&model.model, &state)
+                .expect("Could not load optimizer state");
+        }
         model
     }
     pub fn reset_target(&mut self) {
-        self.target.state().clone_from(&self.model.state());
+        self.target = self.model.clone();
     }
-    pub fn get_action(
+    /**
+     * Actions of a batch of agents from a single forward pass, with epsilon-greedy exploration
+     * drawn from each agent's own generator. Also returns the next hidden states, which are empty
+     * for feed-forward models.
+     */
+    pub fn get_actions(
         &self,
-        state: &AgentState,
+        states: &[&AgentState],
+        hidden: &[&[f32]],
         config: &AgentConfig,
         world_limits: (Vec2, Vec2),
         learning: bool,
-    ) -> Action {
-        let output = self.model.forward(state_to_tensor(
-            state,
-            &NormalizationData {
-                min_speed: 0.0,
-                max_speed: config.run_speed,
-                min_loc: world_limits.0,
-                max_loc: world_limits.1,
-                min_energy: 0.0,
-                max_energy: 100.0,
-                min_dist: 0.0,
-                max_dist: config.vision_range,
-            },
-        ));
-        tensor_to_action(&output, self.eps, learning, &mut rand::thread_rng())
+        rngs: &mut [&mut ChaCha12Rng],
+    ) -> Vec<(Action, Vec<f32>)> {
+        if states.is_empty() {
+            return Vec::new();
+        }
+        let batch_size = states.len();
+        // Agents without a hidden state yet start from zeros
+        let hs = self.model.recurrent_size().map(|size| {
+            let data = hidden
+                .iter()
+                .flat_map(|h| {
+                    if h.is_empty() {
+                        vec![0.0; size]
+                    } else {
+                        h.to_vec()
+                    }
+                })
+                .collect();
+            Tensor::from_floats(Data::new(data, Shape::from([batch_size, size])))
+        });
+        let (output, hs) = self.model.forward(
+            states_to_tensor(states, &NormalizationData::new(config, world_limits)),
+            hs,
+        );
+        let outputs = output.dims()[1];
+        let output = output.to_data().value;
+        let hs = hs.map(|hs| hs.to_data().value);
+        output
+            .chunks(outputs)
+            .zip(rngs.iter_mut())
+            .enumerate()
+            .map(|(i, (row, rng))| {
+                let hidden = hs.as_ref().map_or_else(Vec::new, |hs| {
+                    let size = hs.len() / batch_size;
+                    hs[i * size..(i + 1) * size].to_vec()
+                });
+                (output_to_action(row, self.eps, learning, rng), hidden)
+            })
+            .collect()
+    }
+}
+
+/**
+ * Used to name saved models, so the latest one is loaded by default
+ */
+pub fn timestamp() -> String {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    since_the_epoch.as_millis().to_string()
+}
+
+/**
+ * Runs a batch of sequences (chronological buffer indices) through the model and returns the
+ * outputs of the last step. Shorter sequences are left-padded, keeping a zero hidden state until
+ * their first transition.
+ */
+fn unroll<B: Backend<FloatElem = f32>>(
+    model: &Model<B>,
+    sequences: &[Vec<usize>],
+    buffer: &ReplayBuffer,
+    norm: &NormalizationData,
+) -> (Tensor<B, 2>, Option<Tensor<B, 2>>) {
+    let batch_size = sequences.len();
+    let length = sequences.iter().map(Vec::len).max().expect("Empty batch");
+    let mut output = None;
+    let mut hs = model
+        .recurrent_size()
+        .map(|size| Tensor::zeros([batch_size, size]));
+    for step in 0..length {
+        // Padded steps repeat the first transition, their hidden state is masked out below
+        let states = sequences
+            .iter()
+            .map(|s| &buffer.get(s[(step + s.len()).saturating_sub(length)]).state)
+            .collect::<Vec<_>>();
+        let (o, h) = model.forward(states_to_tensor(&states, norm), hs.clone());
+        output = Some(o);
+        hs = match (h, hs) {
+            (Some(h), Some(previous)) if sequences.iter().any(|s| s.len() + step < length) => {
+                let size = h.dims()[1];
+                let mask = Tensor::<B, 2>::from_floats(Data::new(
+                    sequences
+                        .iter()
+                        .flat_map(|s| vec![f32::from(u8::from(s.len() + step >= length)); size])
+                        .collect(),
+                    Shape::from([batch_size, size]),
+                ));
+                Some(
+                    h.mul(mask.clone())
+                        .add(previous.mul(mask.neg().add_scalar(1.0))),
+                )
+            }
+            (h, _) => h,
+        };
     }
+    (output.expect("Empty sequence"), hs)
 }
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+
+use bevy::prelude::{Entity, Vec2};
+use burn::{
+    module::{Module, Param, State},
+    nn,
+    optim::{Adam, AdamConfig, GradientsParams, Optimizer},
+    tensor::{backend::Backend, Data, Shape, Tensor},
+};
+use burn_autodiff::ADBackendDecorator;
+use rand::{distributions::WeightedIndex, prelude::Distribution, seq::SliceRandom};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entities::{Action, AgentState, AgentType},
+    helpers::config_parser::{AgentConfig, Algorithm, Config, RLConfig},
+};
+
+use super::{
+    model::{
+        argmax, save_state, set_path_param_ids, state_to_tensor, states_to_tensor, ModelBackend,
+        NormalizationData,
+    },
+    snapshot::{check_compatibility, save_snapshot},
+    Transition, UpdateStats,
+};
+
+type PPOBackend = ADBackendDecorator<ModelBackend>;
+
+/**
+ * On-policy storage of the transitions collected since the last update, as one trajectory per
+ * agent life
+ */
+#[derive(Default, Debug)]
+pub struct RolloutBuffer {
+    pub trajectories: Vec<Vec<Transition>>,
+    active: HashMap<Entity, usize>,
+}
+impl RolloutBuffer {
+    pub fn push(&mut self, e: Entity, t: Transition) {
+        let idx = match self.active.get(&e) {
+            Some(idx) => *idx,
+            None => {
+                self.trajectories.push(Vec::new());
+                self.active.insert(e, self.trajectories.len() - 1);
+                self.trajectories.len() - 1
+            }
+        };
+        if t.done {
+            self.active.remove(&e);
+        }
+        self.trajectories[idx].push(t);
+    }
+    /**
+     * Ends the trajectory of an agent without a terminal state (or of all agents), so the
+     * remaining return is estimated by the critic
+     */
+    pub fn truncate(&mut self, e: Option<Entity>) {
+        if let Some(e) = e {
+            self.active.remove(&e);
+        } else {
+            self.active.clear();
+        }
+    }
+    pub fn clear(&mut self) {
+        self.trajectories.clear();
+        self.active.clear();
+    }
+}
+
+/**
+ * Generalized advantage estimates of a trajectory, `bootstrap` is the value of the state
+ * following its last transition
+ */
+pub fn gae(
+    rewards: &[f32],
+    values: &[f32],
+    dones: &[bool],
+    bootstrap: f32,
+    discount: f32,
+    lambda: f32,
+) -> Vec<f32> {
+    let mut advantages = vec![0.0; rewards.len()];
+    let mut next_value = bootstrap;
+    let mut next_advantage = 0.0;
+    for i in (0..rewards.len()).rev() {
+        let non_terminal = if dones[i] { 0.0 } else { 1.0 };
+        let delta = rewards[i] + discount * non_terminal * next_value - values[i];
+        next_advantage = delta + discount * lambda * non_terminal * next_advantage;
+        advantages[i] = next_advantage;
+        next_value = values[i];
+    }
+    advantages
+}
+
+/**
+ * Shared hidden layers with a policy head (action logits) and a value head
+ */
+#[derive(Module, Debug)]
+pub struct ActorCritic<B: Backend<FloatElem = f32>> {
+    pub lr: f32,
+    hidden_layers: Param<Vec<nn::Linear<B>>>,
+    policy_head: Param<nn::Linear<B>>,
+    value_head: Param<nn::Linear<B>>,
+    hidden_activation: nn::ReLU,
+}
+impl<B: Backend<FloatElem = f32>> ActorCritic<B> {
+    pub fn new(inputs: usize, outputs: usize, hidden_layers: &[usize], lr: f32) -> Self {
+        let layers = [&[inputs], hidden_layers].concat();
+        let hidden_layers = layers
+            .windows(2)
+            .map(|w| nn::Linear::new(&nn::LinearConfig::new(w[0], w[1]).with_bias(true)))
+            .collect::<Vec<_>>();
+        let last_size = *layers.last().unwrap();
+        let model = Self {
+            lr,
+            hidden_layers: Param::from(hidden_layers),
+            policy_head: Param::from(nn::Linear::new(
+                &nn::LinearConfig::new(last_size, outputs).with_bias(true),
+            )),
+            value_head: Param::from(nn::Linear::new(
+                &nn::LinearConfig::new(last_size, 1).with_bias(true),
+            )),
+            hidden_activation: nn::ReLU::default(),
+        };
+        let mut state = model.state();
+        set_path_param_ids(&mut state, "model");
+        model
+            .load(&state)
+            .expect("Could not reassign parameter ids")
+    }
+    /**
+     * Returns the action logits `[batch, outputs]` and the state values `[batch, 1]`
+     */
+    pub fn forward(&self, mut xs: Tensor<B, 2>) -> (Tensor<B, 2>, Tensor<B, 2>) {
+        for layer in self.hidden_layers.iter() {
+            xs = layer.forward(xs);
+            xs = self.hidden_activation.forward(xs);
+        }
+        (
+            self.policy_head.forward(xs.clone()),
+            self.value_head.forward(xs),
+        )
+    }
+}
+
+/**
+ * Log-softmax over the actions. burn's version broadcasts, which autodiff does not support.
+ */
+fn log_softmax<B: Backend<FloatElem = f32>>(logits: Tensor<B, 2>) -> Tensor<B, 2> {
+    let [batch_size, outputs] = logits.dims();
+    let data = logits.to_data().value;
+    let max = data
+        .chunks(outputs)
+        .flat_map(|row| {
+            let max = row.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+            vec![max; outputs]
+        })
+        .collect();
+    let shifted = logits.sub(Tensor::from_floats(Data::new(
+        max,
+        Shape::from([batch_size, outputs]),
+    )));
+    let log_sum = shifted.clone().exp().sum_dim(1).log();
+    shifted.sub(log_sum.repeat(1, outputs))
+}
+
+#[derive(Serialize, Deserialize)]
+struct PPODescription {
+    algorithm: Algorithm,
+    lr: f32,
+    counter: usize,
+    layers: Vec<usize>,
+    agent_type: String,
+}
+
+pub struct PPOModel {
+    pub model: ActorCritic<PPOBackend>,
+    pub opt: Adam<PPOBackend>,
+    pub counter: usize,
+    layers: Vec<usize>,
+    agent_type: AgentType,
+}
+impl PPOModel {
+    pub fn new(
+        inputs: usize,
+        outputs: usize,
+        hidden_layers: &[usize],
+        lr: f32,
+        agent_type: AgentType,
+    ) -> Self {
+        Self {
+            model: ActorCritic::new(inputs, outputs, hidden_layers, lr),
+            opt: Adam::new(&AdamConfig::new(lr as f64)),
+            counter: 0,
+            layers: [&[inputs], hidden_layers, &[outputs]].concat(),
+            agent_type,
+        }
+    }
+    /**
+     * Runs `cfg.ppo.epochs` epochs of minibatch updates of the clipped surrogate objective over
+     * the collected trajectories
+     */
+    pub fn train(
+        &mut self,
+        rollout: &RolloutBuffer,
+        norm: &NormalizationData,
+        cfg: &RLConfig,
+        rng: &mut ChaCha12Rng,
+    ) -> UpdateStats {
+        let ppo = &cfg.ppo;
+        let outputs = *self.layers.last().unwrap();
+        let mut states: Vec<Tensor<PPOBackend, 2>> = Vec::new();
+        let mut actions = Vec::new();
+        let mut advantages = Vec::new();
+        let mut returns = Vec::new();
+        let mut value_sum = 0f32;
+        for trajectory in rollout.trajectories.iter().filter(|t| !t.is_empty()) {
+            let last = trajectory.last().unwrap();
+            let mut trajectory_states = trajectory
+                .iter()
+                .map(|t| state_to_tensor(&t.state, norm))
+                .collect::<Vec<_>>();
+            trajectory_states.push(state_to_tensor(&last.next_state, norm));
+            let (_, values) = self
+                .model
+                .forward(Tensor::cat(trajectory_states.clone(), 0));
+            let values = values.to_data().value;
+            let bootstrap = if last.done {
+                0.0
+            } else {
+                values[trajectory.len()]
+            };
+            let trajectory_advantages = gae(
+                &trajectory.iter().map(|t| t.reward).collect::<Vec<_>>(),
+                &values[..trajectory.len()],
+                &trajectory.iter().map(|t| t.done).collect::<Vec<_>>(),
+                bootstrap,
+                cfg.discount,
+                ppo.gae_lambda,
+            );
+            trajectory_states.pop();
+            value_sum += values[..trajectory.len()].iter().sum::<f32>();
+            states.append(&mut trajectory_states);
+            actions.extend(trajectory.iter().map(|t| t.action.to_action_index()));
+            returns.extend(
+                trajectory_advantages
+                    .iter()
+                    .zip(&values)
+                    .map(|(a, v)| a + v),
+            );
+            advantages.extend(trajectory_advantages);
+        }
+        if states.is_empty() {
+            return UpdateStats::default();
+        }
+
+        let n = states.len();
+        let mean = advantages.iter().sum::<f32>() / n as f32;
+        let std = (advantages.iter().map(|a| (a - mean).powi(2)).sum::<f32>() / n as f32).sqrt();
+        for a in advantages.iter_mut() {
+            *a = (*a - mean) / (std + 1e-8);
+        }
+        let old_log_probs = {
+            let (logits, _) = self.model.forward(Tensor::cat(states.clone(), 0));
+            let log_probs = log_softmax(logits).to_data().value;
+            (0..n)
+                .map(|i| log_probs[i * outputs + actions[i]])
+                .collect::<Vec<_>>()
+        };
+
+        let column = |batch: &[usize], data: &[f32]| {
+            Tensor::<PPOBackend, 2>::from_floats(Data::new(
+                batch.iter().map(|i| data[*i]).collect(),
+                Shape::from([batch.len(), 1]),
+            ))
+        };
+        let mut loss_sum = 0f32;
+        let mut num_batches = 0;
+        let mut indices = (0..n).collect::<Vec<_>>();
+        for _ in 0..ppo.epochs {
+            indices.shuffle(rng);
+            for batch in indices.chunks(cfg.batch_size) {
+                let (logits, values) = self.model.forward(Tensor::cat(
+                    batch.iter().map(|i| states[*i].clone()).collect(),
+                    0,
+                ));
+                let log_probs = log_softmax(logits);
+                let mut action_mask = vec![0.0; batch.len() * outputs];
+                for (row, i) in batch.iter().enumerate() {
+                    action_mask[row * outputs + actions[*i]] = 1.0;
+                }
+                let action_log_probs = log_probs
+                    .clone()
+                    .mul(Tensor::from_floats(Data::new(
+                        action_mask,
+                        Shape::from([batch.len(), outputs]),
+                    )))
+                    .sum_dim(1);
+                let ratio = action_log_probs.sub(column(batch, &old_log_probs)).exp();
+                // The clipped objective has no gradient where the ratio left the trust region
+                // in the direction favoured by the advantage
+                let clipped_advantages = ratio
+                    .to_data()
+                    .value
+                    .iter()
+                    .zip(batch)
+                    .map(|(r, i)| {
+                        let a = advantages[*i];
+                        if (a >= 0.0 && *r > 1.0 + ppo.clip) || (a < 0.0 && *r < 1.0 - ppo.clip) {
+                            0.0
+                        } else {
+                            a
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let policy_loss = ratio
+                    .mul(Tensor::from_floats(Data::new(
+                        clipped_advantages,
+                        Shape::from([batch.len(), 1]),
+                    )))
+                    .mean()
+                    .neg();
+                let value_loss = values
+                    .sub(column(batch, &returns))
+                    .powf(2.0)
+                    .mean()
+                    .mul_scalar(ppo.value_coef);
+                let entropy = log_probs
+                    .clone()
+                    .exp()
+                    .mul(log_probs)
+                    .sum_dim(1)
+                    .mean()
+                    .neg();
+                let loss = policy_loss
+                    .add(value_loss)
+                    .sub(entropy.mul_scalar(ppo.entropy_coef));
+                loss_sum += loss.clone().single_value();
+                num_batches += 1;
+                let grads = GradientsParams::from_grads(loss.backward(), &self.model);
+                self.model = self.opt.update_module(self.model.clone(), grads);
+            }
+        }
+        self.counter += 1;
+        UpdateStats {
+            loss: loss_sum / num_batches as f32,
+            mean_value: value_sum / n as f32,
+        }
+    }
+    /**
+     * Writes `<name>.toml`, `.model`, `.config.toml` and the optimizer state (`.optim`)
+     */
+    pub fn save(&self, path: &str, name: &str, config: &Config) {
+        let cfg = PPODescription {
+            algorithm: Algorithm::Ppo,
+            lr: self.model.lr,
+            counter: self.counter,
+            layers: self.layers.clone(),
+            agent_type: match self.agent_type {
+                AgentType::Prey => "prey".to_string(),
+                AgentType::Predator => "predator".to_string(),
+            },
+        };
+        let toml_cfg_string = toml::to_string(&cfg).expect("Could not serialize model description");
+        std::fs::write(format!("{path}/{name}.toml"), toml_cfg_string)
+            .expect("Could not write model description");
+        save_snapshot(&format!("{path}/{name}"), config, self.agent_type);
+        save_state(&self.model.state(), &format!("{path}/{name}.model"));
+        save_state(
+            &self.opt.state(&self.model),
+            &format!("{path}/{name}.optim"),
+        );
+    }
+    /**
+     * Loads a saved model, panicking if its sensors or actions do not match the config
+     */
+    pub fn load(path: &str, model_name: &str, config: &Config) -> Self {
+        let cfg_string = std::fs::read_to_string(format!("{path}/{model_name}.toml"))
+            .expect("Could not read cfg file");
+        let cfg: PPODescription = toml::from_str(&cfg_string).expect("Could not parse cfg file");
+        let mut model = Self::new(
+            cfg.layers[0],
+            cfg.layers[cfg.layers.len() - 1],
+            &cfg.layers[1..cfg.layers.len() - 1],
+            cfg.lr,
+            match cfg.agent_type.as_str() {
+                "prey" => AgentType::Prey,
+                "predator" => AgentType::Predator,
+                _ => panic!("Unknown agent type"),
+            },
+        );
+        check_compatibility(
+            &format!("{path}/{model_name}"),
+            config,
+            model.agent_type,
+            cfg.layers[0],
+            cfg.layers[cfg.layers.len() - 1],
+        );
+        model.counter = cfg.counter;
+        model.model = model
+            .model
+            .load(&State::load(format!("{path}/{model_name}.model").as_str()).unwrap())
+            .unwrap();
+        model.model.lr = config.rl.learning_rate;
+        if let Ok(state) = State::load(format!("{path}/{model_name}.optim").as_str()) {
+            model
+                .opt
+                .load(&model.model, &state)
+                .expect("Could not load optimizer state");
+        }
+        model
+    }
+    /**
+     * Actions of a batch of agents from a single forward pass. Samples from the policy while
+     * learning, otherwise takes the most probable action.
+     */
+    pub fn get_actions(
+        &self,
+        states: &[&AgentState],
+        config: &AgentConfig,
+        world_limits: (Vec2, Vec2),
+        learning: bool,
+        rngs: &mut [&mut ChaCha12Rng],
+    ) -> Vec<Action> {
+        if states.is_empty() {
+            return Vec::new();
+        }
+        let (logits, _) = self.model.forward(states_to_tensor(
+            states,
+            &NormalizationData::new(config, world_limits),
+        ));
+        let outputs = logits.dims()[1];
+        let logits = logits.to_data().value;
+        logits
+            .chunks(outputs)
+            .zip(rngs.iter_mut())
+            .map(|(logits, rng)| {
+                if learning {
+                    let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+                    let weights = WeightedIndex::new(logits.iter().map(|l| (l - max).exp()))
+                        .expect("Invalid action probabilities");
+                    Action::from_action_index(weights.sample(rng))
+                } else {
+                    Action::from_action_index(argmax(logits))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod ppo_tests {
+    use super::gae;
+
+    #[test]
+    fn test_gae() {
+        let rewards = [1.0, 0.0, 2.0];
+        let values = [0.5, 1.0, 1.5];
+        // Without bootstrapping and with lambda = 1, advantages are returns minus values
+        let advantages = gae(&rewards, &values, &[false, false, true], 10.0, 0.5, 1.0);
+        let returns = [1.0 + 0.25 * 2.0, 0.5 * 2.0, 2.0];
+        for i in 0..3 {
+            assert!((advantages[i] - (returns[i] - values[i])).abs() < 1e-6);
+        }
+        // With lambda = 0, advantages are one-step TD errors
+        let advantages = gae(&rewards, &values, &[false, false, false], 4.0, 0.5, 0.0);
+        assert!((advantages[0] - (1.0 + 0.5 * 1.0 - 0.5)).abs() < 1e-6);
+        assert!((advantages[2] - (2.0 + 0.5 * 4.0 - 1.5)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entities::AgentType,
+    helpers::config_parser::{AgentConfig, Config, RLConfig, WorldConfig},
+};
+
+/**
+ * Inputs describing the agent itself: location, direction, speed, energy and environment
+ */
+const SELF_SIZE: usize = 11;
+const VISION_RAY_SIZE: usize = 15;
+const HEARING_RAY_SIZE: usize = 10;
+pub const NUM_ACTIONS: usize = 11;
+
+/**
+ * Sizes of the model inputs and outputs implied by an agent's sensors
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ObservationLayout {
+    pub self_size: usize,
+    pub vision_rays: usize,
+    pub vision_ray_size: usize,
+    pub hearing_rays: usize,
+    pub hearing_ray_size: usize,
+    pub inputs: usize,
+    pub actions: usize,
+}
+impl ObservationLayout {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            self_size: SELF_SIZE,
+            vision_rays: config.vision_rays,
+            vision_ray_size: VISION_RAY_SIZE,
+            hearing_rays: config.hearing_rays,
+            hearing_ray_size: HEARING_RAY_SIZE,
+            inputs: SELF_SIZE
+                + config.vision_rays * VISION_RAY_SIZE
+                + config.hearing_rays * HEARING_RAY_SIZE,
+            actions: NUM_ACTIONS,
+        }
+    }
+    /**
+     * Fields which differ, as `(name, checkpoint value, config value)`
+     */
+    fn differences(&self, other: &Self) -> Vec<(&'static str, usize, usize)> {
+        [
+            ("self_size", self.self_size, other.self_size),
+            ("vision_rays", self.vision_rays, other.vision_rays),
+            (
+                "vision_ray_size",
+                self.vision_ray_size,
+                other.vision_ray_size,
+            ),
+            ("hearing_rays", self.hearing_rays, other.hearing_rays),
+            (
+                "hearing_ray_size",
+                self.hearing_ray_size,
+                other.hearing_ray_size,
+            ),
+            ("inputs", self.inputs, other.inputs),
+            ("actions", self.actions, other.actions),
+        ]
+        .into_iter()
+        .filter(|(_, a, b)| a != b)
+        .collect()
+    }
+}
+
+/**
+ * Config a checkpoint was trained with, written to `<checkpoint>.config.toml`
+ */
+#[derive(Serialize)]
+struct ConfigSnapshot<'a> {
+    observation: ObservationLayout,
+    world: &'a WorldConfig,
+    rl: &'a RLConfig,
+    agent: &'a AgentConfig,
+}
+
+/**
+ * Only the observation layout of a snapshot, so snapshots of older configs can still be checked
+ */
+#[derive(Deserialize)]
+struct SnapshotObservation {
+    observation: ObservationLayout,
+}
+
+fn agent_config(config: &Config, agent_type: AgentType) -> &AgentConfig {
+    match agent_type {
+        AgentType::Prey => &config.prey,
+        AgentType::Predator => &config.predator,
+    }
+}
+
+/**
+ * Writes the snapshot of a checkpoint, `checkpoint` is its path without an extension
+ */
+pub fn save_snapshot(checkpoint: &str, config: &Config, agent_type: AgentType) {
+    let agent = agent_config(config, agent_type);
+    let snapshot = ConfigSnapshot {
+        observation: ObservationLayout::new(agent),
+        world: &config.world,
+        rl: &config.rl,
+        agent,
+    };
+    let snapshot = toml::to_string(&snapshot).expect("Could not serialize config snapshot");
+    std::fs::write(format!("{checkpoint}.config.toml"), snapshot)
+        .expect("Could not write config snapshot");
+}
+
+/**
+ * Panics with every difference if a checkpoint's sensors or actions do not match the active
+ * config. Checkpoints without a snapshot are checked by their input and output sizes only.
+ */
+pub fn check_compatibility(
+    checkpoint: &str,
+    config: &Config,
+    agent_type: AgentType,
+    inputs: usize,
+    outputs: usize,
+) {
+    let expected = ObservationLayout::new(agent_config(config, agent_type));
+    let differences = match std::fs::read_to_string(format!("{checkpoint}.config.toml")) {
+        Ok(snapshot) => toml::from_str::<SnapshotObservation>(&snapshot)
+            .expect("Could not parse config snapshot")
+            .observation
+            .differences(&expected),
+        Err(_) => [
+            ("inputs", inputs, expected.inputs),
+            ("actions", outputs, expected.actions),
+        ]
+        .into_iter()
+        .filter(|(_, a, b)| a != b)
+        .collect(),
+    };
+    if !differences.is_empty() {
+        let differences = differences
+            .iter()
+            .map(|(name, a, b)| format!("\n    {name}: {a} in the checkpoint, {b} in the config"))
+            .collect::<String>();
+        panic!("Model {checkpoint} does not match the config:{differences}");
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::helpers::config_parser::read_config;
+
+    #[test]
+    fn test_compatibility() {
+        let mut config = read_config(Some(std::path::Path::new("config.toml")), &[]);
+        let checkpoint = std::env::temp_dir().join(format!("prey_{}", std::process::id()));
+        let checkpoint = checkpoint.to_str().unwrap();
+        save_snapshot(checkpoint, &config, AgentType::Prey);
+        let inputs = ObservationLayout::new(&config.prey).inputs;
+        check_compatibility(checkpoint, &config, AgentType::Prey, inputs, NUM_ACTIONS);
+
+        config.prey.vision_rays += 1;
+        let result = std::panic::catch_unwind(|| {
+            check_compatibility(checkpoint, &config, AgentType::Prey, inputs, NUM_ACTIONS)
+        });
+        std::fs::remove_file(format!("{checkpoint}.config.toml")).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+/**
+ * Binary tree where every node holds the sum of its children, used for sampling leaves
+ * proportionally to their value (priority).
+ *
+ *          1
+ *      2       3
+ *    4   5   6   7   <- leaves
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SumTree {
+    leaves: usize,
+    tree: Vec<f32>,
+}
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        let leaves = capacity.next_power_of_two();
+        Self {
+            leaves,
+            tree: vec![0.0; 2 * leaves],
+        }
+    }
+    pub fn total(&self) -> f32 {
+        self.tree[1]
+    }
+    pub fn get(&self, i: usize) -> f32 {
+        self.tree[self.leaves + i]
+    }
+    pub fn set(&mut self, i: usize, value: f32) {
+        let mut idx = self.leaves + i;
+        self.tree[idx] = value;
+        // Recompute instead of adding the difference, so rounding errors do not accumulate
+        while idx > 1 {
+            idx /= 2;
+            self.tree[idx] = self.tree[2 * idx] + self.tree[2 * idx + 1];
+        }
+    }
+    /**
+     * Returns the leaf at which the cumulative sum reaches `value` (0 <= value < total)
+     */
+    pub fn find(&self, mut value: f32) -> usize {
+        let mut idx = 1;
+        while idx < self.leaves {
+            let left = 2 * idx;
+            if value < self.tree[left] || self.tree[left + 1] <= 0.0 {
+                idx = left;
+            } else {
+                value -= self.tree[left];
+                idx = left + 1;
+            }
+        }
+        idx - self.leaves
+    }
+}
+
+#[cfg(test)]
+mod sum_tree_tests {
+    use super::SumTree;
+
+    #[test]
+    fn test_total() {
+        let mut tree = SumTree::new(5);
+        for (i, p) in [1.0, 2.0, 3.0, 4.0, 5.0].into_iter().enumerate() {
+            tree.set(i, p);
+        }
+        assert_eq!(tree.total(), 15.0);
+        tree.set(2, 0.5);
+        assert_eq!(tree.total(), 12.5);
+        assert_eq!(tree.get(2), 0.5);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut tree = SumTree::new(3);
+        tree.set(0, 1.0);
+        tree.set(1, 0.0);
+        tree.set(2, 3.0);
+        assert_eq!(tree.find(0.0), 0);
+        assert_eq!(tree.find(0.99), 0);
+        assert_eq!(tree.find(1.0), 2);
+        assert_eq!(tree.find(3.5), 2);
+        // Values past the total must not land on empty leaves
+        assert_eq!(tree.find(4.0), 2);
+    }
+}
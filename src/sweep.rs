@@ -0,0 +1,463 @@
+use std::{
+    collections::BTreeMap,
+    process::{Command, Stdio},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use toml::Value;
+
+use crate::{
+    evaluation::STATISTICS,
+    helpers::{
+        cli::{apply_override, Args},
+        config_parser::{read_config_table, Config},
+    },
+};
+
+/**
+ * Config tables whose keys may be swept
+ */
+const SWEPT_TABLES: [&str; 3] = ["rl", "prey", "predator"];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SweepMethod {
+    Grid,
+    Random,
+}
+
+/**
+ * Values of a swept key: a list, or a range which random search samples uniformly (or
+ * log-uniformly)
+ */
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Parameter {
+    Values(Vec<Value>),
+    Range {
+        min: f64,
+        max: f64,
+        log: Option<bool>,
+        integer: Option<bool>,
+    },
+}
+impl Parameter {
+    fn sample(&self, rng: &mut StdRng) -> Value {
+        match self {
+            Self::Values(values) => values[rng.gen_range(0..values.len())].clone(),
+            Self::Range {
+                min,
+                max,
+                log,
+                integer,
+            } => {
+                let value = if log.unwrap_or(false) {
+                    rng.gen_range(min.ln()..=max.ln()).exp()
+                } else {
+                    rng.gen_range(*min..=*max)
+                };
+                if integer.unwrap_or(false) {
+                    Value::Integer(value.round() as i64)
+                } else {
+                    Value::Float(value)
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A sweep read from a toml file. Every run trains with the base config, the `fixed` overrides and
+ * its own parameter values, and is then evaluated. Runs are ranked by the mean of the evaluation
+ * statistic `metric`.
+ */
+#[derive(Deserialize, Debug)]
+pub struct SweepSpec {
+    pub method: SweepMethod,
+    pub samples: Option<usize>, // Runs of a random search
+    pub parallel: Option<usize>,
+    pub save_path: String,
+    pub seed: Option<u64>,
+    pub metric: String,
+    pub maximize: Option<bool>,
+    pub fixed: Option<BTreeMap<String, Value>>,
+    pub parameters: BTreeMap<String, Parameter>,
+}
+impl SweepSpec {
+    pub fn load(path: &str) -> Self {
+        let spec = std::fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Could not read sweep spec {path}"));
+        toml::from_str(&spec).unwrap_or_else(|e| panic!("Could not parse sweep spec {path}: {e}"))
+    }
+    /**
+     * Problems which would only show once runs are sampled or ranked
+     */
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self
+            .parameters
+            .iter()
+            .filter_map(|(key, p)| match p {
+                Parameter::Values(values) if values.is_empty() => {
+                    Some(format!("{key} has no values"))
+                }
+                Parameter::Range { min, max, .. } if !min.is_finite() || !max.is_finite() => {
+                    Some(format!("{key} needs a finite min and max"))
+                }
+                Parameter::Range { min, max, .. } if min > max => {
+                    Some(format!("{key} has min {min} above max {max}"))
+                }
+                Parameter::Range { min, log, .. } if log.unwrap_or(false) && *min <= 0.0 => Some(
+                    format!("{key} is logarithmic but min {min} is not positive"),
+                ),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !STATISTICS.contains(&self.metric.as_str()) {
+            problems.push(format!(
+                "unknown metric {}, expected one of {STATISTICS:?}",
+                self.metric
+            ));
+        }
+        problems
+    }
+    /**
+     * Parameter values of every run
+     */
+    pub fn runs(&self) -> Vec<Vec<(String, Value)>> {
+        match self.method {
+            SweepMethod::Grid => self
+                .parameters
+                .iter()
+                .fold(vec![Vec::new()], |runs, (key, p)| {
+                    let Parameter::Values(values) = p else {
+                        panic!("Grid sweeps need a list of values for {key}");
+                    };
+                    runs.iter()
+                        .flat_map(|run| {
+                            values.iter().map(move |value| {
+                                let mut run = run.clone();
+                                run.push((key.clone(), value.clone()));
+                                run
+                            })
+                        })
+                        .collect()
+                }),
+            SweepMethod::Random => {
+                let mut rng = match self.seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                };
+                let samples = self
+                    .samples
+                    .expect("Random sweeps need a number of samples");
+                (0..samples)
+                    .map(|_| {
+                        self.parameters
+                            .iter()
+                            .map(|(key, p)| (key.clone(), p.sample(&mut rng)))
+                            .collect()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RunResult {
+    dir: String,
+    parameters: Vec<(String, Value)>,
+    score: Option<f32>,
+    summary: serde_json::Value,
+    error: Option<String>,
+}
+
+/**
+ * Writes the config of a run to its directory, returning why it is invalid if it is
+ */
+fn write_run_config(
+    base: &toml::Table,
+    fixed: &[(String, Value)],
+    parameters: &[(String, Value)],
+    dir: &str,
+) -> Result<String, String> {
+    let mut table = base.clone();
+    for (key, value) in fixed.iter().chain(parameters) {
+        apply_override(&mut table, key, value.clone());
+    }
+    for (key, value) in [
+        ("rl.learn", Value::Boolean(true)),
+        ("rl.headless", Value::Boolean(true)),
+        ("rl.save_path", Value::String(dir.to_string())),
+        ("rl.checkpoints.resume", Value::Boolean(false)),
+    ] {
+        apply_override(&mut table, key, value);
+    }
+    // Runs start from fresh models
+    if let Some(Value::Table(rl)) = table.get_mut("rl") {
+        for key in ["load_path", "load_step", "load_model_name"] {
+            rl.remove(key);
+        }
+    }
+    let config: Config = Value::Table(table.clone())
+        .try_into()
+        .map_err(|e| format!("{e}"))?;
+    if let Err(errors) = config.validate() {
+        let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        return Err(errors.join("; "));
+    }
+    std::fs::create_dir_all(dir).expect("Could not create run directory");
+    let path = format!("{dir}/config.toml");
+    let config = toml::to_string(&table).expect("Could not serialize run config");
+    std::fs::write(&path, config).expect("Could not write run config");
+    Ok(path)
+}
+
+/**
+ * Runs this executable with the given arguments, appending its output to the run's log
+ */
+fn run_child(dir: &str, stage: &str, args: &[&str]) -> Result<(), String> {
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{dir}/log.txt"))
+        .expect("Could not create run log");
+    let exe = std::env::current_exe().expect("Could not find the executable");
+    let status = Command::new(exe)
+        .args(args)
+        .stdout(Stdio::from(
+            log.try_clone().expect("Could not open run log"),
+        ))
+        .stderr(Stdio::from(log))
+        .status()
+        .map_err(|e| format!("Could not start run: {e}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{stage} failed with {status}, see {dir}/log.txt"))
+    }
+}
+
+/**
+ * Summary of the latest evaluation written to a run's directory
+ */
+fn read_evaluation(dir: &str) -> Result<serde_json::Value, String> {
+    let latest = std::fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|f| f.ok()?.file_name().into_string().ok())
+        .filter(|f| f.starts_with("eval_") && f.ends_with(".json"))
+        .max()
+        .ok_or("No evaluation written")?;
+    let evaluation =
+        std::fs::read_to_string(format!("{dir}/{latest}")).map_err(|e| e.to_string())?;
+    let evaluation: serde_json::Value =
+        serde_json::from_str(&evaluation).map_err(|e| e.to_string())?;
+    Ok(evaluation["summary"].clone())
+}
+
+fn run(
+    base: &toml::Table,
+    spec: &SweepSpec,
+    i: usize,
+    parameters: Vec<(String, Value)>,
+) -> RunResult {
+    let dir = format!("{}/run_{i:03}", spec.save_path);
+    let fixed = spec
+        .fixed
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    let result = write_run_config(base, &fixed, &parameters, &dir).and_then(|config| {
+        println!("Run {i}: training");
+        run_child(&dir, "Training", &["--config", &config])?;
+        println!("Run {i}: evaluating");
+        run_child(
+            &dir,
+            "Evaluation",
+            &["--config", &config, "--eval", "--load", &dir],
+        )?;
+        read_evaluation(&dir)
+    });
+    match result {
+        Ok(summary) => {
+            let score = summary[&spec.metric]["mean"].as_f64().map(|s| s as f32);
+            println!("Run {i}: {} = {score:?}", spec.metric);
+            RunResult {
+                dir,
+                parameters,
+                score,
+                summary,
+                error: None,
+            }
+        }
+        Err(error) => {
+            println!("Run {i}: {error}");
+            RunResult {
+                dir,
+                parameters,
+                score: None,
+                summary: serde_json::Value::Null,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+/**
+ * Trains and evaluates every run of a sweep, `parallel` at a time in separate processes, then
+ * ranks them in `sweep_summary.json` in the sweep's save path
+ */
+pub fn run_sweep(args: &Args, spec_path: &str) {
+    let spec = SweepSpec::load(spec_path);
+    let base = read_config_table(args.config.as_deref(), &args.overrides);
+    let unknown = spec
+        .parameters
+        .keys()
+        .chain(spec.fixed.iter().flat_map(BTreeMap::keys))
+        .filter(|key| {
+            !SWEPT_TABLES
+                .iter()
+                .any(|t| key.starts_with(&format!("{t}.")))
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        unknown.is_empty(),
+        "Only keys of {SWEPT_TABLES:?} can be swept, not {unknown:?}"
+    );
+    let problems = spec.problems();
+    assert!(
+        problems.is_empty(),
+        "Invalid sweep spec {spec_path}: {}",
+        problems.join(", ")
+    );
+    let runs = spec.runs();
+    println!("Sweep of {} runs", runs.len());
+    std::fs::create_dir_all(&spec.save_path).expect("Could not create sweep directory");
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(spec.parallel.unwrap_or(1))
+        .build()
+        .expect("Could not start sweep threads");
+    let mut results = pool.install(|| {
+        runs.into_par_iter()
+            .enumerate()
+            .map(|(i, parameters)| run(&base, &spec, i, parameters))
+            .collect::<Vec<_>>()
+    });
+
+    // Failed runs last
+    let maximize = spec.maximize.unwrap_or(true);
+    results.sort_by(|a, b| match (a.score, b.score) {
+        (Some(a), Some(b)) if maximize => b.total_cmp(&a),
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    println!("{:<8}{:<24}{:>12}  parameters", "rank", "run", spec.metric);
+    for (rank, result) in results.iter().enumerate() {
+        let parameters = result
+            .parameters
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>();
+        let score = result
+            .score
+            .map_or_else(|| "-".to_string(), |s| format!("{s:.3}"));
+        println!(
+            "{:<8}{:<24}{score:>12}  {}",
+            rank + 1,
+            result.dir,
+            parameters.join(" ")
+        );
+    }
+
+    let summary = results
+        .iter()
+        .map(|r| {
+            let parameters = r
+                .parameters
+                .iter()
+                .map(|(k, v)| (k.clone(), json!(v)))
+                .collect::<serde_json::Map<_, _>>();
+            json!({
+                "dir": r.dir,
+                "parameters": parameters,
+                "score": r.score,
+                "evaluation": r.summary,
+                "error": r.error,
+            })
+        })
+        .collect::<Vec<_>>();
+    let summary = json!({"metric": spec.metric, "maximize": maximize, "runs": summary});
+    let path = format!("{}/sweep_summary.json", spec.save_path);
+    std::fs::write(&path, summary.to_string()).expect("Could not write sweep summary");
+    println!("Sweep summary written to {path}");
+}
+
+#[cfg(test)]
+mod sweep_tests {
+    use super::*;
+
+    #[test]
+    fn test_runs() {
+        let mut spec: SweepSpec = toml::from_str(
+            r#"
+            method = "grid"
+            save_path = "sweeps"
+            metric = "predator_score"
+            [parameters]
+            "rl.learning_rate" = [0.001, 0.0001]
+            "rl.layers" = [[32], [64, 64], [128]]
+            "#,
+        )
+        .unwrap();
+        let runs = spec.runs();
+        assert_eq!(runs.len(), 6);
+        let layers = Value::Array(vec![Value::Integer(128)]);
+        assert_eq!(runs[5][0], ("rl.layers".to_string(), layers));
+
+        spec.method = SweepMethod::Random;
+        spec.samples = Some(20);
+        spec.seed = Some(1);
+        spec.parameters.insert(
+            "prey.vision_rays".to_string(),
+            Parameter::Range {
+                min: 4.0,
+                max: 16.0,
+                log: None,
+                integer: Some(true),
+            },
+        );
+        let runs = spec.runs();
+        assert_eq!(runs.len(), 20);
+        assert_eq!(runs, spec.runs());
+        for run in runs {
+            let (key, rays) = &run[0];
+            assert_eq!(key, "prey.vision_rays");
+            let rays = rays.as_integer().unwrap();
+            assert!((4..=16).contains(&rays));
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut spec: SweepSpec = toml::from_str(
+            r#"
+            method = "random"
+            save_path = "sweeps"
+            metric = "predator_scor"
+            [parameters]
+            "rl.learning_rate" = { min = 0.0, max = 0.01, log = true }
+            "rl.discount" = { min = 0.99, max = 0.9 }
+            "rl.batch_size" = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(spec.problems().len(), 4);
+        spec.metric = "predator_score".to_string();
+        spec.parameters.clear();
+        assert!(spec.problems().is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+use bevy::prelude::*;
+use serde_json::json;
+
+use crate::{
+    entities::AgentType,
+    evaluation::Evaluation,
+    helpers::config_parser::Config,
+    rl::{checkpoint::Manifest, learner::Learner, model_helpers::timestamp},
+};
+
+const ELO_BASE: f32 = 1500.0;
+const ELO_SCALE: f32 = 400.0;
+const ELO_ITERATIONS: usize = 200;
+const ELO_K: f32 = 16.0;
+
+/**
+ * A saved model taking part in a tournament
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entrant {
+    pub label: String,
+    path: String,
+    name: String,
+}
+impl Entrant {
+    fn load(&self, config: &Config) -> Learner {
+        let mut learner = Learner::load(&self.path, &self.name, config);
+        learner.disable_exploration();
+        learner
+    }
+}
+
+/**
+ * Models of a species in a directory: every checkpoint of its manifest, then every model saved by
+ * name (`prey_<name>`, `predator_<name>`), each ordered by step or name
+ */
+pub fn entrants(path: &str, agent_type: AgentType) -> Vec<Entrant> {
+    let prefix = match agent_type {
+        AgentType::Prey => "prey",
+        AgentType::Predator => "predator",
+    };
+    let mut entrants = Manifest::load(path)
+        .map(|manifest| {
+            manifest
+                .checkpoints
+                .iter()
+                .map(|c| Entrant {
+                    label: c.dir.clone(),
+                    path: format!("{path}/{}", c.dir),
+                    name: prefix.to_string(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let mut named = std::fs::read_dir(path)
+        .unwrap_or_else(|_| panic!("Could not read tournament directory {path}"))
+        .filter_map(|f| {
+            let file = f.ok()?.path();
+            if file.extension()? != "model" {
+                return None;
+            }
+            let stem = file.file_stem()?.to_str()?;
+            let (t, label) = stem.split_once('_')?;
+            (t == prefix).then(|| Entrant {
+                label: label.to_string(),
+                path: path.to_string(),
+                name: stem.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+    named.sort_by(|a, b| a.label.cmp(&b.label));
+    entrants.extend(named);
+    entrants
+}
+
+/**
+ * Ratings on the Elo scale which best explain the scores of a round robin, `scores[predator][prey]`
+ * being the mean predator score of a pairing. Fitted with repeated batch Elo updates, which keep the
+ * mean rating at the base rating.
+ */
+pub fn elo_ratings(scores: &[Vec<f32>]) -> (Vec<f32>, Vec<f32>) {
+    let mut predators = vec![ELO_BASE; scores.len()];
+    let mut prey = vec![ELO_BASE; scores.first().map_or(0, Vec::len)];
+    let games = prey.len().max(predators.len()).max(1) as f32;
+    for _ in 0..ELO_ITERATIONS {
+        let mut predator_deltas = vec![0.0; predators.len()];
+        let mut prey_deltas = vec![0.0; prey.len()];
+        for (j, row) in scores.iter().enumerate() {
+            for (i, score) in row.iter().enumerate() {
+                let expected = 1.0 / (1.0 + 10f32.powf((prey[i] - predators[j]) / ELO_SCALE));
+                predator_deltas[j] += score - expected;
+                prey_deltas[i] -= score - expected;
+            }
+        }
+        for (r, d) in predators.iter_mut().zip(predator_deltas) {
+            *r += ELO_K * d / games;
+        }
+        for (r, d) in prey.iter_mut().zip(prey_deltas) {
+            *r += ELO_K * d / games;
+        }
+    }
+    (prey, predators)
+}
+
+/**
+ * Plays every prey model against every predator model for `[eval] episodes` episodes each.
+ * Present only in tournament mode.
+ */
+#[derive(Resource)]
+pub struct Tournament {
+    prey: Vec<Entrant>,
+    predators: Vec<Entrant>,
+    scores: Vec<Vec<f32>>, // Mean predator score of each finished pairing, by predator and prey
+    output: String,
+}
+impl Tournament {
+    pub fn new(path: &str, config: &Config) -> Self {
+        let prey = entrants(path, AgentType::Prey);
+        let predators = entrants(path, AgentType::Predator);
+        assert!(
+            !prey.is_empty() && !predators.is_empty(),
+            "Tournament directory {path} needs prey and predator models"
+        );
+        println!(
+            "Tournament of {} prey and {} predator models",
+            prey.len(),
+            predators.len()
+        );
+        Self {
+            prey,
+            predators,
+            scores: vec![Vec::new()],
+            output: format!("{}/tournament_{}.json", config.rl.save_path, timestamp()),
+        }
+    }
+    /**
+     * Prey and predator indices of the pairing being played
+     */
+    fn pairing(&self) -> (usize, usize) {
+        (
+            self.scores.last().map_or(0, Vec::len),
+            self.scores.len() - 1,
+        )
+    }
+    /**
+     * Models of the pairing being played
+     */
+    pub fn models(&self, config: &Config) -> (Learner, Learner) {
+        let (prey, predator) = self.pairing();
+        println!(
+            "Match {}/{}: prey {} against predator {}",
+            predator * self.prey.len() + prey + 1,
+            self.prey.len() * self.predators.len(),
+            self.prey[prey].label,
+            self.predators[predator].label
+        );
+        (
+            self.prey[prey].load(config),
+            self.predators[predator].load(config),
+        )
+    }
+    /**
+     * Records the finished episodes of the pairing being played. Returns false after the last
+     * pairing.
+     */
+    pub fn end_match(&mut self, evaluation: &mut Evaluation) -> bool {
+        let score = evaluation
+            .summary()
+            .iter()
+            .find(|(name, _)| *name == "predator_score")
+            .map_or(0.5, |(_, [mean, ..])| *mean);
+        evaluation.restart();
+        let row = self.scores.last_mut().unwrap();
+        row.push(score);
+        if row.len() < self.prey.len() {
+            return true;
+        }
+        if self.scores.len() < self.predators.len() {
+            self.scores.push(Vec::new());
+            return true;
+        }
+        false
+    }
+    /**
+     * Prints the score matrix and the ratings, and writes them to `tournament_<timestamp>.json` in
+     * the save path
+     */
+    pub fn report(&self) {
+        let (prey_ratings, predator_ratings) = elo_ratings(&self.scores);
+        println!("Predator scores (rows: predators, columns: prey)");
+        print!("{:<24}", "");
+        for prey in &self.prey {
+            print!("{:>24}", prey.label);
+        }
+        println!();
+        for (predator, row) in self.predators.iter().zip(&self.scores) {
+            print!("{:<24}", predator.label);
+            for score in row {
+                print!("{score:>24.3}");
+            }
+            println!();
+        }
+        println!("Elo ratings");
+        for (entrant, rating) in self.prey.iter().zip(&prey_ratings) {
+            println!("{:<12}{:<24}{rating:>12.1}", "prey", entrant.label);
+        }
+        for (entrant, rating) in self.predators.iter().zip(&predator_ratings) {
+            println!("{:<12}{:<24}{rating:>12.1}", "predator", entrant.label);
+        }
+
+        let ratings = |entrants: &[Entrant], ratings: &[f32]| {
+            entrants
+                .iter()
+                .zip(ratings)
+                .map(|(e, r)| json!({"label": e.label, "rating": r}))
+                .collect::<Vec<_>>()
+        };
+        let report = json!({
+            "prey": ratings(&self.prey, &prey_ratings),
+            "predators": ratings(&self.predators, &predator_ratings),
+            "predator_scores": self.scores,
+        });
+        std::fs::write(&self.output, report.to_string()).expect("Could not write tournament");
+        println!("Tournament written to {}", self.output);
+    }
+}
+
+#[cfg(test)]
+mod tournament_tests {
+    use super::*;
+
+    #[test]
+    fn test_elo_ratings() {
+        // The second predator beats both prey, the first loses to the second prey
+        let scores = vec![vec![0.5, 0.2], vec![0.9, 0.8]];
+        let (prey, predators) = elo_ratings(&scores);
+        assert!(predators[1] > predators[0]);
+        assert!(prey[1] > prey[0]);
+        let mean = prey.iter().chain(&predators).sum::<f32>() / 4.0;
+        assert!((mean - ELO_BASE).abs() < 1e-2);
+    }
+}